@@ -0,0 +1,754 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::GzDecoder;
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tar::{Archive, EntryType};
+use uuid::Uuid;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::spool::{STAGING_PATH, TEMP_PREFIX};
+use crate::tenant::BASE_PATH;
+use crate::time::{civil_from_days, days_from_civil};
+
+/// The `ustar` magic of the tar headers, at offset 257
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const EMPTY_ZIP_MAGIC: &[u8] = b"PK\x05\x06";
+/// The file type bits of a unix mode, and the ones of a symlink
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+/// The longest symlink target read from a zip entry
+const MAX_LINK_LENGTH: u64 = 4096;
+
+/// This enum defines the archive formats supported by `compress` and `decompress`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+/// This struct defines an entry of an archive, as it is read before the extraction
+///
+/// # Attributes
+/// * path (PathBuf): the path of the entry inside the archive
+/// * entry_type (EntryType): the type of the entry, the zip entries are mapped to the tar types
+/// * size (u64): the uncompressed size in bytes
+/// * link (Option<PathBuf>): the target of a symlink or of a hardlink
+pub struct RawEntry {
+    pub path: PathBuf,
+    pub entry_type: EntryType,
+    pub size: u64,
+    pub link: Option<PathBuf>,
+}
+
+/// This struct defines the progress of an archive operation, shared with the job running it. The
+/// operation stops at the next entry once it has been cancelled.
+///
+/// # Attributes
+/// * entries (AtomicU64): the number of entries processed
+/// * bytes (AtomicU64): the uncompressed size in bytes of the entries processed
+/// * total_entries (AtomicU64): the number of entries to process, `0` while unknown
+/// * total_bytes (AtomicU64): the uncompressed size in bytes to process, `0` while unknown
+/// * cancelled (AtomicBool): `true` once the operation has been cancelled
+#[derive(Default, Debug)]
+pub struct Progress {
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    total_entries: AtomicU64,
+    total_bytes: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    /// This function records a processed entry, or fails with `Interrupted` if the operation has
+    /// been cancelled.
+    pub fn advance(&self, bytes: u64) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "The operation has been cancelled",
+            ));
+        }
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The totals are known once the archive has been inspected, the counters restart from zero.
+    pub fn start(&self, total_entries: u64, total_bytes: u64) {
+        self.entries.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+        self.total_entries.store(total_entries, Ordering::Relaxed);
+        self.total_bytes.store(total_bytes, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn total_entries(&self) -> Option<u64> {
+        Some(self.total_entries.load(Ordering::Relaxed)).filter(|total| *total > 0)
+    }
+
+    pub fn total_bytes(&self) -> Option<u64> {
+        Some(self.total_bytes.load(Ordering::Relaxed)).filter(|total| *total > 0)
+    }
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::TarZst => "application/zstd",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+
+    /// This function detects the format of an archive from its magic bytes. The plain tar archives
+    /// written without the `ustar` magic are recognized by the extension of their name.
+    ///
+    /// # Arguments
+    /// * file (&File): the open archive
+    /// * path (&Path): the name of the archive
+    ///
+    /// # Returns
+    /// (io::Result<ArchiveFormat>): the format, an `InvalidData` error if it is not supported
+    pub fn detect(file: &File, path: &Path) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(TAR_MAGIC_OFFSET + TAR_MAGIC.len());
+        rewind(file)?
+            .take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+            .read_to_end(&mut header)?;
+
+        if header.starts_with(ZIP_MAGIC) || header.starts_with(EMPTY_ZIP_MAGIC) {
+            return Ok(ArchiveFormat::Zip);
+        }
+        if header.starts_with(GZIP_MAGIC) {
+            return Ok(ArchiveFormat::TarGz);
+        }
+        if header.starts_with(ZSTD_MAGIC) {
+            return Ok(ArchiveFormat::TarZst);
+        }
+        if header.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC) {
+            return Ok(ArchiveFormat::Tar);
+        }
+
+        let name = path.to_string_lossy().to_lowercase();
+        match name.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("zip") => Ok(ArchiveFormat::Zip),
+            Some("gz") | Some("tgz") => Ok(ArchiveFormat::TarGz),
+            Some("zst") | Some("tzst") => Ok(ArchiveFormat::TarZst),
+            Some("tar") => Ok(ArchiveFormat::Tar),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported archive format, expected zip, tar.gz, tar.zst or tar",
+            )),
+        }
+    }
+}
+
+/// The archive is opened once and read again from the start for every pass, so the entries that
+/// have been checked are the ones extracted, even if the archive is replaced in the meantime.
+fn rewind(file: &File) -> io::Result<&File> {
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(reader)
+}
+
+fn open_tar(file: &File, format: ArchiveFormat) -> io::Result<Archive<Box<dyn Read + '_>>> {
+    let file = rewind(file)?;
+    let reader: Box<dyn Read> = match format {
+        ArchiveFormat::TarGz => Box::new(GzDecoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(zstd::Decoder::new(file)?),
+        _ => Box::new(file),
+    };
+    Ok(Archive::new(reader))
+}
+
+fn open_zip(file: &File) -> io::Result<ZipArchive<BufReader<&File>>> {
+    Ok(ZipArchive::new(BufReader::new(rewind(file)?))?)
+}
+
+/// This function reads the entries of an archive, without extracting them.
+///
+/// # Arguments
+/// * file (&File): the open archive
+/// * format (ArchiveFormat): the format of the archive
+/// * visit (FnMut(RawEntry) -> bool): called for every entry, the reading stops when it returns
+///   `false`
+///
+/// # Returns
+/// (io::Result<()>): an error if the archive cannot be read
+pub fn for_each_entry(
+    file: &File,
+    format: ArchiveFormat,
+    mut visit: impl FnMut(RawEntry) -> bool,
+) -> io::Result<()> {
+    if format != ArchiveFormat::Zip {
+        let mut archive = open_tar(file, format)?;
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let entry_type = entry.header().entry_type();
+            if matches!(entry_type, EntryType::XGlobalHeader | EntryType::XHeader) {
+                continue;
+            }
+            let raw = RawEntry {
+                path: entry.path()?.into_owned(),
+                entry_type,
+                size: entry.size(),
+                link: entry.link_name()?.map(|link| link.into_owned()),
+            };
+            if !visit(raw) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut archive = open_zip(file)?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let is_symlink = file.unix_mode().map(|mode| mode & S_IFMT) == Some(S_IFLNK);
+        let (entry_type, link) = if file.is_dir() {
+            (EntryType::Directory, None)
+        } else if is_symlink {
+            (EntryType::Symlink, Some(read_symlink_target(&mut file)?))
+        } else {
+            (EntryType::Regular, None)
+        };
+        let raw = RawEntry {
+            path: PathBuf::from(file.name()),
+            entry_type,
+            size: file.size(),
+            link,
+        };
+        if !visit(raw) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// This function removes the first `count` segments from the path of an archive entry, the `.`
+/// segments are ignored. The path is kept as it is when a segment to remove is not a plain one, so
+/// the entry is rejected by the checks instead of being made relative.
+///
+/// # Arguments
+/// * path (&Path): the path of the entry inside the archive
+/// * count (usize): the number of leading segments to remove
+///
+/// # Returns
+/// (Option<PathBuf>): the stripped path, `None` if nothing is left and the entry is skipped
+pub fn strip_components(path: &Path, count: usize) -> Option<PathBuf> {
+    let mut components = path
+        .components()
+        .filter(|component| *component != Component::CurDir);
+    for _ in 0..count {
+        match components.next() {
+            Some(Component::Normal(_)) => {}
+            Some(_) => return Some(path.to_path_buf()),
+            None => return None,
+        }
+    }
+    let stripped: PathBuf = components.collect();
+    if stripped.as_os_str().is_empty() {
+        None
+    } else {
+        Some(stripped)
+    }
+}
+
+/// The target of a zip symlink is the content of its entry.
+fn read_symlink_target(file: &mut impl Read) -> io::Result<PathBuf> {
+    let mut target = String::new();
+    file.take(MAX_LINK_LENGTH).read_to_string(&mut target)?;
+    Ok(PathBuf::from(target))
+}
+
+/// This function extracts an archive under `root`, removing the first `strip` segments from the
+/// path of every entry. The entries must have been checked already, with the same `strip`.
+///
+/// # Arguments
+/// * file (&File): the open archive, the one whose entries have been checked
+/// * format (ArchiveFormat): the format of the archive
+/// * root (&Path): the directory the entries are extracted in
+/// * strip (usize): the number of leading segments removed from the entry paths
+/// * progress (&Progress): updated after every entry
+///
+/// # Returns
+/// (io::Result<()>): an error if an entry cannot be extracted or the extraction has been cancelled
+pub fn unpack(
+    file: &File,
+    format: ArchiveFormat,
+    root: &Path,
+    strip: usize,
+    progress: &Progress,
+) -> io::Result<()> {
+    fs::create_dir_all(root)?;
+    if format != ArchiveFormat::Zip {
+        let mut archive = open_tar(file, format)?;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_type = entry.header().entry_type();
+            if matches!(entry_type, EntryType::XGlobalHeader | EntryType::XHeader) {
+                continue;
+            }
+            let name = match strip_components(&entry.path()?, strip) {
+                Some(name) => name,
+                None => continue,
+            };
+            let target = entry_target(root, &name)?;
+            if entry_type == EntryType::Link {
+                // the hardlinks name a file already extracted in `root`, with the same segments
+                // removed, never a file that was there before or a symlink
+                let link = entry
+                    .link_name()?
+                    .and_then(|link| strip_components(&link, strip))
+                    .ok_or_else(|| unpack_error(&name))?;
+                let source = entry_target(root, &link)?;
+                if !source.symlink_metadata()?.is_file() {
+                    return Err(unpack_error(&name));
+                }
+                remove_existing(&target)?;
+                fs::hard_link(source, &target)?;
+            } else {
+                entry.unpack(&target)?;
+            }
+            progress.advance(entry.size())?;
+        }
+        return Ok(());
+    }
+
+    let mut archive = open_zip(file)?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = file
+            .enclosed_name()
+            .map(|name| name.to_path_buf())
+            .ok_or_else(|| unpack_error(Path::new(file.name())))?;
+        let name = match strip_components(&name, strip) {
+            Some(name) => name,
+            None => continue,
+        };
+        let target = entry_target(root, &name)?;
+        if file.is_dir() {
+            fs::create_dir_all(&target)?;
+            progress.advance(0)?;
+            continue;
+        }
+        remove_existing(&target)?;
+
+        let mode = file.unix_mode();
+        if mode.map(|mode| mode & S_IFMT) == Some(S_IFLNK) {
+            symlink(read_symlink_target(&mut file)?, &target)?;
+            progress.advance(0)?;
+            continue;
+        }
+        let size = file.size();
+        let modified = zip_time(file.last_modified());
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode.map(|mode| mode & 0o777).unwrap_or(0o644))
+            .open(&target)?;
+        // the declared size is the limit checked before the extraction
+        io::copy(&mut (&mut file).take(size), &mut out)?;
+        if let Some(modified) = modified {
+            out.set_modified(modified)?;
+        }
+        progress.advance(size)?;
+    }
+    Ok(())
+}
+
+/// The parent directory of an entry is created and must still be inside `root` once
+/// canonicalized, so nothing is written through a symlink leading outside of it.
+fn entry_target(root: &Path, name: &Path) -> io::Result<PathBuf> {
+    let target = root.join(name);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+        if !parent.canonicalize()?.starts_with(root.canonicalize()?) {
+            return Err(unpack_error(name));
+        }
+    }
+    Ok(target)
+}
+
+/// An existing file is replaced, not written through, as it can be a link to a shared blob.
+fn remove_existing(target: &Path) -> io::Result<()> {
+    match fs::remove_file(target) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn unpack_error(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unable to extract {}", path.display()),
+    )
+}
+
+/// This function writes the archive of `source`, a file or a directory, to `writer`.
+///
+/// # Arguments
+/// * source (&Path): the file resource to archive
+/// * name (&Path): the path of `source` inside the archive, empty to archive the children of
+///   `source` with their own names
+/// * format (ArchiveFormat): the format of the archive
+/// * writer (impl Write): where the archive is written
+/// * progress (&Progress): updated after every entry
+///
+/// # Returns
+/// (io::Result<()>): an error if a file cannot be read, the archive cannot be written or the
+/// operation has been cancelled
+pub fn write_archive(
+    source: &Path,
+    name: &Path,
+    format: ArchiveFormat,
+    mut writer: impl Write,
+    progress: &Progress,
+) -> io::Result<()> {
+    match format {
+        ArchiveFormat::TarGz => {
+            let mut tar = tar_builder(GzEncoder::new(writer, Compression::best()));
+            append_tar_entry(&mut tar, source, name, progress)?;
+            tar.into_inner()?.finish()?.flush()
+        }
+        ArchiveFormat::TarZst => {
+            let mut tar = tar_builder(zstd::Encoder::new(writer, 0)?);
+            append_tar_entry(&mut tar, source, name, progress)?;
+            tar.into_inner()?.finish()?.flush()
+        }
+        ArchiveFormat::Tar => {
+            let mut tar = tar_builder(writer);
+            append_tar_entry(&mut tar, source, name, progress)?;
+            tar.into_inner()?.flush()
+        }
+        ArchiveFormat::Zip => {
+            // the zip central directory is written at the end, so the archive is built in the
+            // staging area and then copied to `writer`
+            let staged = Path::new(BASE_PATH)
+                .join(STAGING_PATH)
+                .join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()));
+            let result = write_zip(source, name, &staged, &mut writer, progress);
+            let _ = fs::remove_file(&staged);
+            result
+        }
+    }
+}
+
+fn tar_builder<W: Write>(writer: W) -> tar::Builder<W> {
+    let mut tar = tar::Builder::new(writer);
+    // the symlinks are archived as symlinks, as `decompress` restores them
+    tar.follow_symlinks(false);
+    tar
+}
+
+/// The tenant root has no name of its own, so its children are archived one by one.
+fn append_tar_entry<W: Write>(
+    tar: &mut tar::Builder<W>,
+    source: &Path,
+    name: &Path,
+    progress: &Progress,
+) -> io::Result<()> {
+    if name.as_os_str().is_empty() {
+        for child in sorted_children(source)? {
+            append_tar_entry(tar, &child.path(), Path::new(&child.file_name()), progress)?;
+        }
+        return Ok(());
+    }
+
+    let meta = fs::symlink_metadata(source)?;
+    tar.append_path_with_name(source, name)?;
+    if meta.is_dir() {
+        progress.advance(0)?;
+        for child in sorted_children(source)? {
+            append_tar_entry(tar, &child.path(), &name.join(child.file_name()), progress)?;
+        }
+        return Ok(());
+    }
+    progress.advance(if meta.is_file() { meta.len() } else { 0 })
+}
+
+fn sorted_children(dir: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+    Ok(children)
+}
+
+fn write_zip(
+    source: &Path,
+    name: &Path,
+    staged: &Path,
+    writer: &mut impl Write,
+    progress: &Progress,
+) -> io::Result<()> {
+    if let Some(parent) = staged.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(staged)?;
+    let mut zip = ZipWriter::new(file);
+    append_zip_entry(&mut zip, source, name, progress)?;
+    let mut file = zip.finish()?;
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut file, writer)?;
+    writer.flush()
+}
+
+fn append_zip_entry(
+    zip: &mut ZipWriter<File>,
+    source: &Path,
+    name: &Path,
+    progress: &Progress,
+) -> io::Result<()> {
+    if name.as_os_str().is_empty() {
+        for child in sorted_children(source)? {
+            append_zip_entry(zip, &child.path(), Path::new(&child.file_name()), progress)?;
+        }
+        return Ok(());
+    }
+
+    let meta = fs::symlink_metadata(source)?;
+    let name = name.to_string_lossy();
+    let mut options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(meta.permissions().mode() & 0o777)
+        .large_file(meta.len() >= u32::MAX as u64);
+    if let Some(modified) = meta.modified().ok().and_then(dos_time) {
+        options = options.last_modified_time(modified);
+    }
+
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(source)?;
+        zip.add_symlink(name, target.to_string_lossy(), options)?;
+        progress.advance(0)
+    } else if meta.is_dir() {
+        zip.add_directory(name.as_ref(), options)?;
+        progress.advance(0)?;
+        for child in sorted_children(source)? {
+            let child_name = Path::new(name.as_ref()).join(child.file_name());
+            append_zip_entry(zip, &child.path(), &child_name, progress)?;
+        }
+        Ok(())
+    } else {
+        zip.start_file(name, options)?;
+        io::copy(&mut File::open(source)?, zip)?;
+        progress.advance(meta.len())
+    }
+}
+
+/// The zip entries keep their modification time, in UTC, with the 2 seconds resolution of the
+/// DOS timestamps.
+fn dos_time(time: SystemTime) -> Option<DateTime> {
+    let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let seconds = seconds.rem_euclid(86400);
+    DateTime::from_date_and_time(
+        u16::try_from(year).ok()?,
+        month as u8,
+        day as u8,
+        (seconds / 3600) as u8,
+        (seconds % 3600 / 60) as u8,
+        (seconds % 60) as u8,
+    )
+    .ok()
+}
+
+fn zip_time(time: DateTime) -> Option<SystemTime> {
+    let days = days_from_civil(time.year() as i64, time.month() as i64, time.day() as i64);
+    let seconds = days * 86400
+        + time.hour() as i64 * 3600
+        + time.minute() as i64 * 60
+        + time.second() as i64;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const FORMATS: [ArchiveFormat; 4] = [
+        ArchiveFormat::Zip,
+        ArchiveFormat::TarZst,
+        ArchiveFormat::Tar,
+        ArchiveFormat::TarGz,
+    ];
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("cds-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// This function writes the build of a widget in `dir/site`: two files, an empty directory and
+    /// a symlink.
+    fn widget(dir: &Path) -> PathBuf {
+        let site = dir.join("site");
+        fs::create_dir_all(site.join("css")).unwrap();
+        fs::create_dir_all(site.join("empty")).unwrap();
+        fs::write(site.join("index.html"), "<html></html>").unwrap();
+        fs::write(site.join("css/main.css"), "body {}").unwrap();
+        symlink("index.html", site.join("home.html")).unwrap();
+        site
+    }
+
+    /// This function writes the archive of `source`, with the given name inside it, in `dir` and
+    /// returns it open.
+    fn archive(dir: &Path, source: &Path, name: &str, format: ArchiveFormat) -> File {
+        let path = dir.join(format!("{}.{}", Uuid::new_v4(), format.extension()));
+        let writer = File::create(&path).unwrap();
+        write_archive(source, Path::new(name), format, writer, &Progress::default()).unwrap();
+        File::open(path).unwrap()
+    }
+
+    fn assert_widget(site: &Path) {
+        assert_eq!(fs::read_to_string(site.join("index.html")).unwrap(), "<html></html>");
+        assert_eq!(fs::read_to_string(site.join("css/main.css")).unwrap(), "body {}");
+        assert!(site.join("empty").is_dir());
+        assert_eq!(fs::read_link(site.join("home.html")).unwrap(), Path::new("index.html"));
+    }
+
+    #[test]
+    fn unpacks_the_archives_it_writes() {
+        let dir = temp_dir();
+        let site = widget(&dir);
+        for format in FORMATS {
+            let file = archive(&dir, &site, "public/site", format);
+            assert_eq!(ArchiveFormat::detect(&file, Path::new("upload")).unwrap(), format);
+            let root = dir.join(format!("unpacked-{}", format.extension()));
+            unpack(&file, format, &root, 0, &Progress::default()).unwrap();
+            assert_widget(&root.join("public/site"));
+
+            // without a name the children are archived with their own names
+            let file = archive(&dir, &dir.join("site"), "", format);
+            let root = dir.join(format!("children-{}", format.extension()));
+            unpack(&file, format, &root, 0, &Progress::default()).unwrap();
+            assert_widget(&root);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn detects_the_format_from_the_magic_bytes_before_the_extension() {
+        let dir = temp_dir();
+        let site = widget(&dir);
+        let file = archive(&dir, &site, "site", ArchiveFormat::TarGz);
+        let detected = ArchiveFormat::detect(&file, Path::new("site.zip")).unwrap();
+        assert_eq!(detected, ArchiveFormat::TarGz);
+
+        // a tar written without the ustar magic is recognized by its extension only
+        let path = dir.join("old.tar");
+        let mut tar = tar::Builder::new(File::create(&path).unwrap());
+        let mut header = tar::Header::new_old();
+        header.set_mode(0o644);
+        header.set_size(2);
+        tar.append_data(&mut header, "a.txt", &b"ok"[..]).unwrap();
+        tar.into_inner().unwrap();
+        let file = File::open(&path).unwrap();
+        for name in ["old.tar", "OLD.TAR"] {
+            assert_eq!(ArchiveFormat::detect(&file, Path::new(name)).unwrap(), ArchiveFormat::Tar);
+        }
+        let unknown = ArchiveFormat::detect(&file, Path::new("old.bin")).unwrap_err();
+        assert_eq!(unknown.kind(), io::ErrorKind::InvalidData);
+
+        // an empty zip has only the end of its central directory
+        let path = dir.join("empty");
+        ZipWriter::new(File::create(&path).unwrap()).finish().unwrap();
+        let file = File::open(&path).unwrap();
+        assert_eq!(ArchiveFormat::detect(&file, Path::new("empty")).unwrap(), ArchiveFormat::Zip);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn strips_the_leading_segments() {
+        let strip = |path: &str, count| strip_components(Path::new(path), count);
+        let stripped = |path: &str| Some(PathBuf::from(path));
+        assert_eq!(strip("build/js/main.js", 0), stripped("build/js/main.js"));
+        assert_eq!(strip("build/js/main.js", 1), stripped("js/main.js"));
+        assert_eq!(strip("./build/./js/main.js", 2), stripped("main.js"));
+        // the entries left without a path are skipped
+        assert_eq!(strip("build/", 1), None);
+        assert_eq!(strip("build/js/main.js", 3), None);
+        // a segment that is not a plain one is kept, so the checks reject the entry
+        assert_eq!(strip("../build/main.js", 1), stripped("../build/main.js"));
+        assert_eq!(strip("/build/main.js", 1), stripped("/build/main.js"));
+    }
+
+    #[test]
+    fn unpacks_the_entries_without_their_leading_segments() {
+        let dir = temp_dir();
+        let site = widget(&dir);
+        for format in FORMATS {
+            let file = archive(&dir, &site, "build/site", format);
+            let root = dir.join(format!("unpacked-{}", format.extension()));
+            unpack(&file, format, &root, 2, &Progress::default()).unwrap();
+            assert_widget(&root);
+            assert!(!root.join("build").exists());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,438 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::jwks::TokenClaims;
+use crate::tenant::{Tenant, DEFAULT_TENANT};
+
+const DEFAULT_TENANT_CLAIM: &str = "tenants";
+const ANY_TENANT: &str = "*";
+const PERMISSIVE: &str = "permissive";
+
+/// This enum defines the operations a token can be allowed to perform on the internal API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    Archive,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Delete => write!(f, "delete"),
+            Permission::Archive => write!(f, "archive"),
+        }
+    }
+}
+
+/// This struct defines the mapping between the Keycloak roles and the CDS permissions.
+///
+/// Roles are written as `role` for a realm role and as `client.role` for a client role. An empty
+/// list of roles denies that permission, unless the permissive mode is enabled with
+/// `CDS_AUTHZ=permissive`: every authenticated token is then granted the permissions without roles
+/// and the tokens without the tenant claim can access the `primary` tenant.
+///
+/// # Attributes
+/// * read_roles (Vec<String>): the roles granting `Permission::Read` (`CDS_READ_ROLES`)
+/// * write_roles (Vec<String>): the roles granting `Permission::Write` (`CDS_WRITE_ROLES`)
+/// * delete_roles (Vec<String>): the roles granting `Permission::Delete` (`CDS_DELETE_ROLES`)
+/// * archive_roles (Vec<String>): the roles granting `Permission::Archive` (`CDS_ARCHIVE_ROLES`)
+/// * tenant_claim (String): the claim listing the tenants the token can access (`CDS_TENANT_CLAIM`)
+/// * permissive (bool): the missing roles and tenant claim grant access instead of denying it
+/// * issuer_tenants (HashMap<String, Vec<String>>): the tenants every issuer can grant
+///   (`CDS_ISSUER_TENANTS`), `*` for the admin issuers. Empty when a single issuer grants the
+///   tenants of its claim
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    read_roles: Vec<String>,
+    write_roles: Vec<String>,
+    delete_roles: Vec<String>,
+    archive_roles: Vec<String>,
+    tenant_claim: String,
+    permissive: bool,
+    issuer_tenants: HashMap<String, Vec<String>>,
+}
+
+impl AuthConfig {
+    /// This function builds the authorization configuration from the env vars. With several
+    /// trusted issuers, e.g. one Keycloak realm per tenant, `CDS_ISSUER_TENANTS` must map every
+    /// issuer to the tenants it can grant, so that the admin of a realm cannot grant the tenants of
+    /// another one: `{"https://kc.domain.com/auth/realms/tenant1": ["tenant1"]}`.
+    ///
+    /// # Arguments
+    /// * issuers (&[String]): the issuers trusted by the key store
+    ///
+    /// # Returns
+    /// (Result<AuthConfig, String>): the configuration, or the reason why it is not valid
+    pub fn from_env(issuers: &[String]) -> Result<Self, String> {
+        let issuer_tenants: HashMap<String, Vec<String>> = match env::var("CDS_ISSUER_TENANTS") {
+            Ok(value) => serde_json::from_str(&value)
+                .map_err(|e| format!("$CDS_ISSUER_TENANTS is not valid: {}", e))?,
+            Err(_) if issuers.len() > 1 => {
+                return Err("$CDS_ISSUER_TENANTS is needed with several issuers.".to_string())
+            }
+            Err(_) => HashMap::new(),
+        };
+        let unmapped = issuers.iter().find(|issuer| {
+            !issuer_tenants.is_empty() && !issuer_tenants.contains_key(issuer.as_str())
+        });
+        if let Some(issuer) = unmapped {
+            return Err(format!("$CDS_ISSUER_TENANTS does not map the issuer {}", issuer));
+        }
+
+        Ok(AuthConfig {
+            read_roles: roles_from_env("CDS_READ_ROLES"),
+            write_roles: roles_from_env("CDS_WRITE_ROLES"),
+            delete_roles: roles_from_env("CDS_DELETE_ROLES"),
+            archive_roles: roles_from_env("CDS_ARCHIVE_ROLES"),
+            tenant_claim: env::var("CDS_TENANT_CLAIM")
+                .unwrap_or_else(|_| DEFAULT_TENANT_CLAIM.to_string()),
+            permissive: env::var("CDS_AUTHZ")
+                .map(|mode| mode.trim().eq_ignore_ascii_case(PERMISSIVE))
+                .unwrap_or(false),
+            issuer_tenants,
+        })
+    }
+
+    /// The permissive configuration of the handler tests: no role is required.
+    #[cfg(test)]
+    pub fn permissive() -> Self {
+        AuthConfig {
+            read_roles: Vec::new(),
+            write_roles: Vec::new(),
+            delete_roles: Vec::new(),
+            archive_roles: Vec::new(),
+            tenant_claim: DEFAULT_TENANT_CLAIM.to_string(),
+            permissive: true,
+            issuer_tenants: HashMap::new(),
+        }
+    }
+
+    pub fn is_permissive(&self) -> bool {
+        self.permissive
+    }
+
+    fn roles_for(&self, permission: Permission) -> &[String] {
+        match permission {
+            Permission::Read => &self.read_roles,
+            Permission::Write => &self.write_roles,
+            Permission::Delete => &self.delete_roles,
+            Permission::Archive => &self.archive_roles,
+        }
+    }
+
+    fn is_granted(&self, permission: Permission, roles: &[String]) -> bool {
+        let required = self.roles_for(permission);
+        if required.is_empty() {
+            return self.permissive;
+        }
+        required.iter().any(|role| roles.contains(role))
+    }
+
+    /// This function checks that the issuer of a token can grant every tenant of its tenant claim.
+    /// Only an admin issuer, mapped to `*` in `CDS_ISSUER_TENANTS`, can grant every tenant with a
+    /// `*` tenant claim.
+    ///
+    /// # Arguments
+    /// * issuer (Option<&str>): the issuer of the token
+    /// * allowed (&[String]): the tenants granted by the token
+    ///
+    /// # Returns
+    /// (Result<(), Error>): a `403 Forbidden` error if a tenant is beyond the issuer tenants
+    fn check_issuer(&self, issuer: Option<&str>, allowed: &[String]) -> Result<(), Error> {
+        let issuer = issuer.unwrap_or_default();
+        let grantable = self
+            .issuer_tenants
+            .get(issuer)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if grantable.iter().any(|code| code == ANY_TENANT) {
+            return Ok(());
+        }
+        if allowed.iter().any(|code| code == ANY_TENANT) {
+            return Err(Error::Forbidden(format!(
+                "The issuer {} cannot grant every tenant",
+                issuer
+            )));
+        }
+        if self.issuer_tenants.is_empty() {
+            return Ok(());
+        }
+        match allowed.iter().find(|code| !grantable.contains(code)) {
+            Some(code) => Err(Error::Forbidden(format!(
+                "The issuer {} cannot grant tenant {}",
+                issuer, code
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn roles_from_env(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|role| role.trim().to_string())
+        .filter(|role| !role.is_empty())
+        .collect()
+}
+
+/// This struct defines what the caller of the internal API is allowed to do. It is built from the
+/// claims of the Keycloak token, validated by the `JwtAuth` middleware.
+///
+/// The tenant is read from the `X-Entando-TenantCode` header and must be listed in the tenant claim
+/// of the token, which the issuer of the token must be able to grant. `*` grants every tenant, when
+/// the issuer is an admin issuer. When the header is missing and the token grants a single
+/// tenant, that tenant is used. Tokens without the tenant claim are denied, or can only access the
+/// `primary` tenant in permissive mode.
+///
+/// # Attributes
+/// * tenant (Tenant): the tenant the request is scoped to
+/// * roles (Vec<String>): the realm and client roles of the token
+/// * config (AuthConfig): the roles to permissions mapping
+pub struct Authorization {
+    tenant: Tenant,
+    roles: Vec<String>,
+    config: AuthConfig,
+}
+
+impl Authorization {
+    pub fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+
+    /// This function checks that the token has been granted the given permission.
+    ///
+    /// # Arguments
+    /// * permission (Permission): the permission required by the handler
+    ///
+    /// # Returns
+    /// (Result<(), Error>): a `403 Forbidden` error if the permission is missing
+    pub fn require(&self, permission: Permission) -> Result<(), Error> {
+        if self.config.is_granted(permission, &self.roles) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!(
+                "Missing the {} permission on tenant {}",
+                permission,
+                self.tenant.code()
+            )))
+        }
+    }
+
+    fn from_request_sync(req: &HttpRequest) -> Result<Self, Error> {
+        let config = req
+            .app_data::<web::Data<AuthConfig>>()
+            .ok_or_else(|| Error::Internal("Authorization is not configured".to_string()))?
+            .get_ref()
+            .clone();
+        let claims = req
+            .extensions()
+            .get::<TokenClaims>()
+            .ok_or_else(|| Error::Forbidden("Missing Keycloak claims".to_string()))?
+            .0
+            .clone();
+        let roles = roles(&claims);
+        let allowed = allowed_tenants(claims.get(&config.tenant_claim), config.permissive);
+        config.check_issuer(claims["iss"].as_str(), &allowed)?;
+
+        let tenant = match Tenant::from_header(req)? {
+            Some(tenant) => tenant,
+            None if allowed.len() == 1 && allowed[0] != ANY_TENANT => Tenant::new(&allowed[0])?,
+            None => Tenant::new(DEFAULT_TENANT)?,
+        };
+        if !allowed
+            .iter()
+            .any(|code| code == ANY_TENANT || code == tenant.code())
+        {
+            return Err(Error::Forbidden(format!(
+                "The token is not allowed to access tenant {}",
+                tenant.code()
+            )));
+        }
+
+        Ok(Authorization {
+            tenant,
+            roles,
+            config,
+        })
+    }
+}
+
+/// Realm roles are returned as `role` and client roles as `client.role`.
+fn roles(claims: &Value) -> Vec<String> {
+    let names = |access: &Value| -> Vec<String> {
+        access["roles"]
+            .as_array()
+            .map(|roles| {
+                roles
+                    .iter()
+                    .filter_map(|role| role.as_str().map(|role| role.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let mut roles = names(&claims["realm_access"]);
+    if let Some(clients) = claims["resource_access"].as_object() {
+        for (client, access) in clients {
+            roles.extend(
+                names(access)
+                    .into_iter()
+                    .map(|role| format!("{}.{}", client, role)),
+            );
+        }
+    }
+    roles
+}
+
+/// The tenant claim can be a single string or an array of strings.
+fn allowed_tenants(claim: Option<&Value>, permissive: bool) -> Vec<String> {
+    match claim {
+        Some(Value::String(code)) => vec![code.to_string()],
+        Some(Value::Array(codes)) => codes
+            .iter()
+            .filter_map(|code| code.as_str().map(|code| code.to_string()))
+            .collect(),
+        _ if permissive => vec![DEFAULT_TENANT.to_string()],
+        _ => Vec::new(),
+    }
+}
+
+impl FromRequest for Authorization {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Authorization::from_request_sync(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    use crate::tenant::TENANT_HEADER;
+
+    const REALM_1: &str = "https://kc.test/realms/t1";
+    const REALM_2: &str = "https://kc.test/realms/t2";
+
+    fn config(permissive: bool) -> AuthConfig {
+        AuthConfig {
+            read_roles: vec!["cds-read".to_string()],
+            write_roles: Vec::new(),
+            delete_roles: Vec::new(),
+            archive_roles: Vec::new(),
+            tenant_claim: DEFAULT_TENANT_CLAIM.to_string(),
+            permissive,
+            issuer_tenants: HashMap::new(),
+        }
+    }
+
+    /// This function authorizes a request for `tenant` with a token of the given claims.
+    fn authorize(config: AuthConfig, claims: Value, tenant: &str) -> Result<Authorization, Error> {
+        let req = TestRequest::default()
+            .app_data(web::Data::new(config))
+            .insert_header((TENANT_HEADER, tenant))
+            .to_http_request();
+        req.extensions_mut().insert(TokenClaims(claims));
+        Authorization::from_request_sync(&req)
+    }
+
+    #[test]
+    fn permissions_without_roles_are_denied_by_default() {
+        let roles = vec!["cds-read".to_string()];
+        assert!(config(false).is_granted(Permission::Read, &roles));
+        assert!(!config(false).is_granted(Permission::Write, &roles));
+        assert!(config(true).is_granted(Permission::Write, &roles));
+        assert!(!config(true).is_granted(Permission::Read, &[]));
+    }
+
+    #[test]
+    fn missing_tenant_claim_is_denied_by_default() {
+        assert!(allowed_tenants(None, false).is_empty());
+        assert_eq!(allowed_tenants(None, true), vec![DEFAULT_TENANT.to_string()]);
+        assert_eq!(
+            allowed_tenants(Some(&json!(["t1", "t2"])), false),
+            vec!["t1".to_string(), "t2".to_string()]
+        );
+    }
+
+    #[test]
+    fn issuers_grant_their_own_tenants_only() {
+        let mut config = config(false);
+        config.issuer_tenants = HashMap::from([
+            (REALM_1.to_string(), vec!["t1".to_string()]),
+            (REALM_2.to_string(), vec!["t2".to_string()]),
+        ]);
+        let token = |issuer: &str, tenants: Value| json!({ "iss": issuer, "tenants": tenants });
+
+        assert!(authorize(config.clone(), token(REALM_1, json!(["t1"])), "t1").is_ok());
+        assert!(authorize(config.clone(), token(REALM_2, json!("t2")), "t2").is_ok());
+        // the admin of the first realm granting the tenant of the second one
+        for (claims, tenant) in [
+            (token(REALM_1, json!(["t2"])), "t2"),
+            (token(REALM_1, json!(["t1", "t2"])), "t1"),
+            (token(REALM_1, json!("*")), "t2"),
+            (token("https://kc.test/realms/other", json!(["t1"])), "t1"),
+        ] {
+            match authorize(config.clone(), claims.clone(), tenant) {
+                Err(Error::Forbidden(_)) => {}
+                _ => panic!("{} granted {}", claims, tenant),
+            }
+        }
+
+        // an admin issuer
+        config
+            .issuer_tenants
+            .insert(REALM_1.to_string(), vec![ANY_TENANT.to_string()]);
+        assert!(authorize(config.clone(), token(REALM_1, json!(["t2"])), "t2").is_ok());
+        assert!(authorize(config, token(REALM_1, json!("*")), "t2").is_ok());
+    }
+
+    #[test]
+    fn only_admin_issuers_grant_every_tenant() {
+        let token = json!({ "iss": REALM_1, "tenants": "*" });
+        match authorize(config(false), token.clone(), "t2") {
+            Err(Error::Forbidden(message)) => assert!(message.contains("every tenant")),
+            _ => panic!("the wildcard of a single issuer has been granted"),
+        }
+
+        let mut admin = config(false);
+        admin.issuer_tenants = HashMap::from([(REALM_1.to_string(), vec!["*".to_string()])]);
+        let authorization = authorize(admin, token, "t2").ok().unwrap();
+        assert_eq!(authorization.tenant().code(), "t2");
+    }
+}
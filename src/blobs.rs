@@ -0,0 +1,171 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use crate::spool::temp_path;
+use crate::tenant::BASE_PATH;
+
+/// The content-addressed blobs shared by every tenant. The name starts with a dot, so it can never
+/// be a tenant code.
+pub const BLOBS_PATH: &str = ".blobs";
+
+/// This function returns the path of the blob with the given hex encoded SHA-256 digest:
+/// `entando-data/.blobs/<first 2 digits>/<digest>`.
+pub fn blob_path(sha256: &str) -> PathBuf {
+    Path::new(BASE_PATH)
+        .join(BLOBS_PATH)
+        .join(&sha256[..2])
+        .join(sha256)
+}
+
+/// This function computes the hex encoded SHA-256 digest of a file.
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// This function replaces the file at `path` with a hard link to the blob with the same content,
+/// adding the file to the blob store when its content is new. The number of links of a blob is its
+/// reference count: `1` when only the blob store references it.
+///
+/// The files are never modified in place (they are replaced with a rename), so a blob never changes
+/// once stored. A file replaced with a link to an existing blob is touched, so its modification time
+/// never goes back to the one of the blob.
+///
+/// # Arguments
+/// * path (&Path): a regular file inside a tenant tree
+///
+/// # Returns
+/// (io::Result<()>): an error if the file cannot be read or linked
+pub fn deduplicate(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    let blob = blob_path(&file_sha256(path)?);
+    if let Some(parent) = blob.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    loop {
+        match fs::hard_link(path, &blob) {
+            // the file is the first reference of a new blob
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+        if is_same_file(&metadata, &blob) {
+            return Ok(());
+        }
+        let temp = temp_path(path);
+        match fs::hard_link(&blob, &temp) {
+            Ok(()) => {}
+            // the blob has just been reclaimed, the file becomes its new first reference
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+        if let Err(err) = fs::rename(&temp, path) {
+            let _ = fs::remove_file(&temp);
+            return Err(err);
+        }
+        return fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now());
+    }
+}
+
+/// The file is already a reference of the blob
+fn is_same_file(metadata: &fs::Metadata, blob: &Path) -> bool {
+    fs::metadata(blob)
+        .map(|blob| blob.dev() == metadata.dev() && blob.ino() == metadata.ino())
+        .unwrap_or(false)
+}
+
+/// This function removes the file (or the directory tree) at `path`, with the blobs referenced only
+/// by the removed files.
+///
+/// # Arguments
+/// * path (&Path): a file or a directory inside a tenant tree
+///
+/// # Returns
+/// (io::Result<()>): an error if a file cannot be removed
+pub fn remove(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            remove(&entry?.path())?;
+        }
+        return fs::remove_dir(path);
+    }
+    // the last reference of a blob has two links: the file and the blob itself
+    let blob = match metadata.is_file() && metadata.nlink() == 2 {
+        true => Some(blob_path(&file_sha256(path)?)),
+        false => None,
+    };
+    fs::remove_file(path)?;
+    if let Some(blob) = blob {
+        let unreferenced = fs::metadata(&blob).is_ok_and(|blob| {
+            blob.dev() == metadata.dev() && blob.ino() == metadata.ino() && blob.nlink() == 1
+        });
+        if unreferenced {
+            let _ = fs::remove_file(&blob);
+        }
+    }
+    Ok(())
+}
+
+/// How often the whole blob store is swept, for the blobs left unreferenced by the replaced files
+pub fn sweep_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+/// This function removes the blobs no longer referenced by any tenant file.
+///
+/// A file linked to a blob while it is being reclaimed keeps its content: the blob store only
+/// loses the chance to share it.
+///
+/// # Returns
+/// (io::Result<usize>): the number of removed blobs
+pub fn reclaim() -> io::Result<usize> {
+    let blobs = Path::new(BASE_PATH).join(BLOBS_PATH);
+    let mut removed = 0;
+    let shards = match fs::read_dir(&blobs) {
+        Ok(shards) => shards,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    for shard in shards {
+        for blob in fs::read_dir(shard?.path())? {
+            let blob = blob?;
+            if blob.metadata()?.nlink() == 1 && fs::remove_file(blob.path()).is_ok() {
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
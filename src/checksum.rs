@@ -0,0 +1,137 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use actix_web::http::header::HeaderMap;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::error::Error;
+
+/// The length of a SHA-256 digest, in bytes
+const SHA256_LEN: usize = 32;
+
+/// This function parses the expected SHA-256 digest of a file sent as a hex string.
+///
+/// # Arguments
+/// * value (&str): the hex encoded digest, in any case
+///
+/// # Returns
+/// (Result<String, Error>): the lowercase hex digest or a `400 Bad Request` error
+pub fn parse_sha256(value: &str) -> Result<String, Error> {
+    let value = value.trim();
+    match hex::decode(value) {
+        Ok(bytes) if bytes.len() == SHA256_LEN => Ok(value.to_ascii_lowercase()),
+        _ => Err(Error::BadRequest(format!("Invalid sha256 value: {}", value))),
+    }
+}
+
+/// This function reads the expected SHA-256 digest from the `Content-Digest` (RFC 9530,
+/// `sha-256=:<base64>:`) or the `Digest` (RFC 3230, `SHA-256=<base64>`) request header. The other
+/// algorithms are ignored.
+///
+/// # Arguments
+/// * headers (&HeaderMap): the request headers
+///
+/// # Returns
+/// (Result<Option<String>, Error>): the lowercase hex digest, `None` if no SHA-256 digest has been
+/// sent, or a `400 Bad Request` error if it is not valid
+pub fn sha256_from_headers(headers: &HeaderMap) -> Result<Option<String>, Error> {
+    for (name, wrapped) in [("Content-Digest", true), ("Digest", false)] {
+        let header = match headers.get(name).and_then(|value| value.to_str().ok()) {
+            Some(header) => header,
+            None => continue,
+        };
+        for entry in header.split(',') {
+            let (algorithm, value) = match entry.split_once('=') {
+                Some((algorithm, value)) => (algorithm.trim(), value.trim()),
+                None => continue,
+            };
+            if !algorithm.eq_ignore_ascii_case("sha-256") {
+                continue;
+            }
+            let value = if wrapped {
+                value.trim_matches(':')
+            } else {
+                value
+            };
+            return match STANDARD.decode(value) {
+                Ok(bytes) if bytes.len() == SHA256_LEN => Ok(Some(hex::encode(bytes))),
+                _ => Err(Error::BadRequest(format!("Invalid {} header", name))),
+            };
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    const HELLO_HEX: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const HELLO_BASE64: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+    fn headers(entries: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_the_hex_digests() {
+        assert_eq!(parse_sha256(HELLO_HEX).unwrap(), HELLO_HEX);
+        let upper = format!(" {} ", HELLO_HEX.to_ascii_uppercase());
+        assert_eq!(parse_sha256(&upper).unwrap(), HELLO_HEX);
+        for invalid in ["", "2cf24dba", &format!("{}00", HELLO_HEX), &HELLO_HEX.replace('2', "g")] {
+            assert!(matches!(parse_sha256(invalid), Err(Error::BadRequest(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reads_the_digest_headers() {
+        assert_eq!(sha256_from_headers(&headers(&[])).unwrap(), None);
+
+        let content_digest = format!("sha-512=:AAAA:, sha-256=:{}:", HELLO_BASE64);
+        let parsed = sha256_from_headers(&headers(&[("content-digest", &content_digest)]));
+        assert_eq!(parsed.unwrap().as_deref(), Some(HELLO_HEX));
+
+        let digest = format!("MD5=XUFAKrxLKna5cZ2REBfFkg==, SHA-256={}", HELLO_BASE64);
+        let parsed = sha256_from_headers(&headers(&[("digest", &digest)]));
+        assert_eq!(parsed.unwrap().as_deref(), Some(HELLO_HEX));
+
+        // the other algorithms are ignored
+        let parsed = sha256_from_headers(&headers(&[("digest", "MD5=XUFAKrxLKna5cZ2REBfFkg==")]));
+        assert_eq!(parsed.unwrap(), None);
+
+        for (name, value) in [
+            ("content-digest", "sha-256=:not base64:"),
+            ("content-digest", "sha-256=:AAAA:"),
+            ("digest", "SHA-256=AAAA"),
+        ] {
+            let parsed = sha256_from_headers(&headers(&[(name, value)]));
+            assert!(matches!(parsed, Err(Error::BadRequest(_))), "{}: {}", name, value);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+//! The compressible files (text, scripts, stylesheets, json, xml, svg, wasm) can be served with a
+//! content coding: from a precompressed sibling, `main.js.br` or `main.js.gz` next to `main.js`,
+//! or compressed on the fly.
+
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use actix_web::http::header::{self, AcceptEncoding};
+use actix_web::{web, HttpMessage, HttpRequest};
+use flate2::write::GzEncoder;
+use futures::TryStreamExt;
+use mime_guess::mime::{self, Mime};
+
+use crate::error::Error;
+use crate::serve::content_type;
+use crate::spool::SpooledFile;
+use crate::storage::Storage;
+
+/// The files smaller than this are not worth compressing
+const MIN_COMPRESS_SIZE: u64 = 256;
+
+/// The largest file compressed, on the fly or by the precompression, the larger ones are served as
+/// they are unless they have a precompressed sibling
+const MAX_COMPRESS_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The content codings supported, the most effective first
+const ENCODINGS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+/// This enum defines a content coding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` token
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The extension of the precompressed siblings
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn header(self) -> header::Encoding {
+        match self {
+            Encoding::Brotli => header::Encoding::brotli(),
+            Encoding::Gzip => header::Encoding::gzip(),
+        }
+    }
+
+    /// This function returns the precompressed sibling of `path`, e.g. `main.js.br` for `main.js`.
+    pub fn sibling(self, path: &Path) -> PathBuf {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(self.extension());
+        PathBuf::from(sibling)
+    }
+
+    /// This function compresses `content`, with the best compression for the precompressed
+    /// siblings or with a faster one on the fly.
+    ///
+    /// # Arguments
+    /// * content (&[u8]): the content to compress
+    /// * best (bool): whether to favour the size over the speed
+    ///
+    /// # Returns
+    /// (io::Result<Vec<u8>>): the compressed content
+    pub fn compress(self, content: &[u8], best: bool) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let quality = if best { 11 } else { 5 };
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, quality, 22);
+                writer.write_all(content)?;
+                writer.flush()?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let level = if best {
+                    flate2::Compression::best()
+                } else {
+                    flate2::Compression::default()
+                };
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// This struct defines how the compressible files are encoded, besides serving the precompressed
+/// siblings that already exist.
+///
+/// # Attributes
+/// * precompress (bool): whether the uploaded and extracted files get their `.br` and `.gz`
+///   siblings (`CDS_PRECOMPRESS`)
+/// * compress (bool): whether the files without a sibling are compressed on the fly
+///   (`CDS_COMPRESS`)
+pub struct Encodings {
+    precompress: bool,
+    compress: bool,
+}
+
+impl Encodings {
+    pub fn from_env() -> Self {
+        let enabled = |name: &str| {
+            env::var(name)
+                .map(|value| value.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+        };
+        Encodings::new(enabled("CDS_PRECOMPRESS"), enabled("CDS_COMPRESS"))
+    }
+
+    pub fn new(precompress: bool, compress: bool) -> Self {
+        Encodings {
+            precompress,
+            compress,
+        }
+    }
+
+    /// This function tells whether a compressible file of `size` bytes is compressed on the fly.
+    pub fn compresses(&self, size: u64) -> bool {
+        self.compress && (MIN_COMPRESS_SIZE..=MAX_COMPRESS_SIZE).contains(&size)
+    }
+
+    /// This function replaces the `.br` and `.gz` siblings of a file just stored: the siblings of
+    /// its previous content are removed, and new ones are written when the precompression is
+    /// enabled and the file is compressible. The siblings not smaller than the file are not
+    /// written. A failure is only logged: the file is stored and can still be served as it is.
+    ///
+    /// # Arguments
+    /// * storage (&dyn Storage): the storage backend
+    /// * path (&Path): the file just stored
+    pub async fn precompress(&self, storage: &dyn Storage, path: &Path) {
+        self.precompress_batch(storage, &[path.to_path_buf()]).await
+    }
+
+    /// This function replaces the siblings of several files stored together, like `precompress`,
+    /// but keeps the siblings stored along with them, e.g. shipped in the same archive.
+    ///
+    /// # Arguments
+    /// * storage (&dyn Storage): the storage backend
+    /// * files (&[PathBuf]): the files just stored
+    pub async fn precompress_batch(&self, storage: &dyn Storage, files: &[PathBuf]) {
+        for path in files {
+            if !compressible(&content_type(path)) {
+                continue;
+            }
+            let mut missing = vec![];
+            for encoding in ENCODINGS {
+                let sibling = encoding.sibling(path);
+                if files.contains(&sibling) {
+                    continue;
+                }
+                if let Err(e) = remove_sibling(storage, &sibling).await {
+                    log::warn!("Unable to remove {}: {}", sibling.display(), e);
+                    continue;
+                }
+                missing.push(encoding);
+            }
+            if !self.precompress || missing.is_empty() {
+                continue;
+            }
+            if let Err(e) = write_siblings(storage, path, missing).await {
+                log::warn!("Unable to precompress {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// This function removes the siblings of a file being deleted, so that they are not served for a
+/// new file stored later at the same path. A failure is only logged.
+///
+/// # Arguments
+/// * storage (&dyn Storage): the storage backend
+/// * path (&Path): the file being deleted
+pub async fn remove_siblings(storage: &dyn Storage, path: &Path) {
+    for encoding in ENCODINGS {
+        let sibling = encoding.sibling(path);
+        if let Err(e) = remove_sibling(storage, &sibling).await {
+            log::warn!("Unable to remove {}: {}", sibling.display(), e);
+        }
+    }
+}
+
+async fn remove_sibling(storage: &dyn Storage, sibling: &Path) -> io::Result<()> {
+    match storage.stat(sibling).await? {
+        Some(meta) if !meta.is_dir => storage.delete(sibling).await,
+        _ => Ok(()),
+    }
+}
+
+/// This function writes the `missing` siblings of `precompress`.
+async fn write_siblings(
+    storage: &dyn Storage,
+    path: &Path,
+    missing: Vec<Encoding>,
+) -> Result<(), Error> {
+    let meta = match storage.stat(path).await? {
+        Some(meta) if !meta.is_dir => meta,
+        _ => return Ok(()),
+    };
+    if !(MIN_COMPRESS_SIZE..=MAX_COMPRESS_SIZE).contains(&meta.size) {
+        return Ok(());
+    }
+
+    let content = read_content(storage, path).await?;
+    let compressed = web::block(move || {
+        missing
+            .into_iter()
+            .map(|encoding| Ok((encoding, encoding.compress(&content, true)?)))
+            .collect::<io::Result<Vec<_>>>()
+    })
+    .await??;
+    for (encoding, content) in compressed {
+        if content.len() as u64 >= meta.size {
+            continue;
+        }
+        let sibling = SpooledFile::from_bytes(content).await?;
+        sibling.persist(storage, &encoding.sibling(path)).await?;
+    }
+    Ok(())
+}
+
+/// This function tells whether a content type is worth compressing: the text types and the
+/// structured formats, the media types are already compressed.
+pub fn compressible(content_type: &Mime) -> bool {
+    match (content_type.type_(), content_type.subtype()) {
+        (mime::TEXT, _) => true,
+        (mime::APPLICATION, mime::JAVASCRIPT | mime::JSON | mime::XML) => true,
+        (mime::APPLICATION, subtype) if subtype == "wasm" => true,
+        _ => matches!(content_type.suffix(), Some(mime::JSON | mime::XML)),
+    }
+}
+
+/// This function returns the content codings accepted by the client, the most preferred first,
+/// following the `Accept-Encoding` header.
+pub fn accepted(req: &HttpRequest) -> Vec<Encoding> {
+    let accept = match req.get_header::<AcceptEncoding>() {
+        Some(accept) if !accept.0.is_empty() => accept,
+        _ => return vec![],
+    };
+    let mut remaining = ENCODINGS.to_vec();
+    let mut accepted = vec![];
+    loop {
+        let supported: Vec<header::Encoding> =
+            remaining.iter().map(|encoding| encoding.header()).collect();
+        // the identity is returned once none of the remaining codings is acceptable
+        let preferred = match accept.negotiate(supported.iter()) {
+            Some(preferred) => preferred,
+            None => return accepted,
+        };
+        match remaining.iter().position(|encoding| encoding.header() == preferred) {
+            Some(index) => accepted.push(remaining.remove(index)),
+            None => return accepted,
+        }
+    }
+}
+
+/// This function reads the whole content of a stored file, the callers bound its size with
+/// `MAX_COMPRESS_SIZE`.
+pub async fn read_content(storage: &dyn Storage, path: &Path) -> Result<Vec<u8>, Error> {
+    let content = storage
+        .get(path)
+        .await?
+        .try_fold(Vec::new(), |mut content, chunk| async move {
+            content.extend_from_slice(&chunk);
+            Ok(content)
+        })
+        .await?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use crate::storage;
+
+    #[actix_web::test]
+    async fn replaces_the_siblings_of_the_stored_files() {
+        let root = env::temp_dir().join(format!("cds-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let storage = storage::from_env().unwrap();
+        let content = "console.log('precompressed');\n".repeat(64);
+        let (main, app) = (root.join("main.js"), root.join("app.js"));
+        for path in [&main, &app] {
+            fs::write(path, &content).unwrap();
+            for encoding in ENCODINGS {
+                fs::write(encoding.sibling(path), "previous").unwrap();
+            }
+        }
+        let read = |path: PathBuf| fs::read(path).unwrap();
+        let enabled = Encodings::new(true, false);
+
+        enabled.precompress(&*storage, &main).await;
+        for encoding in ENCODINGS {
+            let sibling = read(encoding.sibling(&main));
+            assert_ne!(sibling, b"previous");
+            assert!(sibling.len() < content.len());
+        }
+
+        // the .br sibling is stored along with the file, the .gz one belongs to the previous content
+        let shipped = Encoding::Brotli.sibling(&app);
+        enabled.precompress_batch(&*storage, &[app.clone(), shipped.clone()]).await;
+        assert_eq!(read(shipped), b"previous");
+        assert_ne!(read(Encoding::Gzip.sibling(&app)), b"previous");
+
+        let disabled = Encodings::new(false, false);
+        disabled.precompress(&*storage, &main).await;
+        remove_siblings(&*storage, &app).await;
+        for encoding in ENCODINGS {
+            assert!(!encoding.sibling(&main).exists());
+            assert!(!encoding.sibling(&app).exists());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
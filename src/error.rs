@@ -0,0 +1,209 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::fmt;
+use std::io;
+
+use actix_multipart::MultipartError;
+use actix_web::body::{BoxBody, EitherBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::error::{BlockingError, PayloadError};
+use actix_web::http::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+/// This enum defines the errors returned by the REST APIs. Every error is returned with its HTTP
+/// status and an `ErrorBody` json.
+#[derive(Debug)]
+pub enum Error {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    PreconditionFailed(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Internal(String),
+    NotImplemented(String),
+}
+
+/// This struct defines the json body of an error response
+///
+/// # Attributes
+/// * code (String): the reason of the HTTP status in snake case, e.g. `not_found`
+/// * message (String): the description of the error
+/// * path (Option<String>): the path of the request
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+impl ErrorBody {
+    fn new(status: StatusCode, message: String, path: Option<String>) -> Self {
+        let code = status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_lowercase()
+            .replace([' ', '-'], "_");
+        ErrorBody {
+            code,
+            message,
+            path,
+        }
+    }
+}
+
+impl Error {
+    /// This function returns the error matching an HTTP status. The other client errors are
+    /// returned as `BadRequest` and the other server errors as `Internal`.
+    pub fn from_status(status: StatusCode, message: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Error::Unauthorized(message),
+            StatusCode::FORBIDDEN => Error::Forbidden(message),
+            StatusCode::NOT_FOUND => Error::NotFound(message),
+            StatusCode::CONFLICT => Error::Conflict(message),
+            StatusCode::PRECONDITION_FAILED => Error::PreconditionFailed(message),
+            StatusCode::PAYLOAD_TOO_LARGE => Error::PayloadTooLarge(message),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Error::UnsupportedMediaType(message),
+            StatusCode::NOT_IMPLEMENTED => Error::NotImplemented(message),
+            status if status.is_client_error() => Error::BadRequest(message),
+            _ => Error::Internal(message),
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Error::BadRequest(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::NotFound(message)
+            | Error::Conflict(message)
+            | Error::PreconditionFailed(message)
+            | Error::PayloadTooLarge(message)
+            | Error::UnsupportedMediaType(message)
+            | Error::Internal(message)
+            | Error::NotImplemented(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        HttpResponse::build(status).json(ErrorBody::new(status, self.to_string(), None))
+    }
+}
+
+/// The storage errors keep their meaning: a missing file is a `404 Not Found`, an existing one a
+/// `409 Conflict`.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(e.to_string()),
+            io::ErrorKind::PermissionDenied => Error::Forbidden(e.to_string()),
+            io::ErrorKind::AlreadyExists => Error::Conflict(e.to_string()),
+            io::ErrorKind::InvalidInput => Error::BadRequest(e.to_string()),
+            _ => {
+                log::error!("{}", e);
+                Error::Internal(e.to_string())
+            }
+        }
+    }
+}
+
+impl From<actix_web::Error> for Error {
+    fn from(e: actix_web::Error) -> Self {
+        Error::from_status(e.as_response_error().status_code(), e.to_string())
+    }
+}
+
+impl From<MultipartError> for Error {
+    fn from(e: MultipartError) -> Self {
+        Error::from(actix_web::Error::from(e))
+    }
+}
+
+impl From<PayloadError> for Error {
+    fn from(e: PayloadError) -> Self {
+        Error::from(actix_web::Error::from(e))
+    }
+}
+
+impl From<BlockingError> for Error {
+    fn from(e: BlockingError) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+/// This function returns the middleware rendering every error response, including the ones of the
+/// extractors and of the routing, as an `ErrorBody` json with the path of the request.
+pub fn handlers<B: 'static>() -> ErrorHandlers<B> {
+    ErrorHandlers::new().default_handler(render)
+}
+
+fn render<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let status = res.status();
+    let message = match res.response().error() {
+        Some(error) => error.to_string(),
+        None => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+    let body = ErrorBody::new(status, message, Some(res.request().path().to_string()));
+    let json = serde_json::to_string(&body)?;
+    let res = res.map_body(|head, _| {
+        head.headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        head.headers.remove(CONTENT_LENGTH);
+        EitherBody::right(BoxBody::new(json))
+    });
+    Ok(ErrorHandlerResponse::Response(res))
+}
@@ -20,15 +20,47 @@
 
 extern crate core;
 
+mod archive;
+mod auth;
+mod blobs;
+mod cache;
+mod checksum;
+mod deploy;
+mod encoding;
+mod error;
 mod handlers;
+mod jobs;
+mod jwks;
+mod s3;
+mod safe_path;
+mod serve;
+mod spool;
+mod storage;
+mod tenant;
+#[cfg(test)]
+mod testing;
+mod time;
+mod trash;
+mod tus;
 mod utils;
+mod versions;
 
 use actix_cors::Cors;
 use actix_web::http::{header, Method};
 use actix_web::{http, middleware, web, App, HttpRequest, HttpResponse, HttpServer};
 use std::env;
+use std::path::Path;
+use std::sync::Arc;
 
-use actix_web_middleware_keycloak_auth::{DecodingKey, KeycloakAuth};
+use auth::AuthConfig;
+use cache::CacheRules;
+use encoding::Encodings;
+use jobs::Jobs;
+use jwks::{JwtAuth, KeyStore};
+use storage::Storage;
+use trash::Trash;
+use tus::TusLocks;
+use versions::Versioning;
 use env_logger::Env;
 
 use futures::future;
@@ -47,20 +79,155 @@ async fn main() -> std::io::Result<()> {
     println!("Internal sever listening on port: {}", INTERNAL_PORT);
     println!("Public server listening on port: {}", PUBLIC_PORT);
 
-    let internal_server = HttpServer::new(move || {
-        let keycloak_auth = KeycloakAuth::default_with_pk(
-            DecodingKey::from_rsa_pem(env::var("KEYCLOAK_PUBLIC_KEY").as_ref().unwrap().as_bytes())
-                .unwrap(),
+    // remove the partial files of the writes interrupted by a crash or a restart
+    let removed = spool::cleanup();
+    if removed > 0 {
+        log::info!("Removed {} leftover temporary files", removed);
+    }
+    jobs::cleanup().expect("unable to remove the files of the previous jobs");
+
+    let storage: web::Data<dyn Storage> =
+        web::Data::from(storage::from_env().expect("unable to configure the storage backend"));
+    let public_storage = storage.clone();
+    if storage.local_path(Path::new(tenant::BASE_PATH)).is_none() {
+        log::warn!(
+            "The releases need the filesystem storage: the releases apis and the deploy mode of \
+            decompress return 501 Not Implemented"
+        );
+    }
+    let key_store = Arc::new(KeyStore::from_env().expect("unable to configure the Keycloak keys"));
+    let auth_config = web::Data::new(
+        AuthConfig::from_env(&key_store.issuers()).expect("unable to configure the authorization"),
+    );
+    if auth_config.is_permissive() {
+        log::warn!(
+            "CDS_AUTHZ=permissive: the permissions without roles are granted to every valid token \
+            and the tokens without the tenant claim can access the primary tenant"
         );
+    }
+    let tus_locks = web::Data::new(TusLocks::default());
+    let versioning = web::Data::new(Versioning::from_env());
+    let trash = web::Data::new(Trash::from_env());
+    let jobs = web::Data::new(Jobs::default());
+    let encodings = web::Data::new(Encodings::from_env());
+    let public_encodings = encodings.clone();
+    let cache_rules =
+        web::Data::new(CacheRules::from_env().expect("unable to configure the cache rules"));
+    let public_cache_rules = cache_rules.clone();
+
+    // fetch the signing keys in the background and keep them up to date: an unreachable Keycloak
+    // does not prevent the startup, the keys are fetched again with the first token of each issuer
+    let refreshed_key_store = key_store.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(KeyStore::refresh_interval());
+        loop {
+            interval.tick().await;
+            refreshed_key_store.refresh_all().await;
+        }
+    });
+
+    // purge the deleted files once their retention period expires
+    if trash.is_enabled() {
+        let expiring_trash = trash.clone();
+        let expiring_storage = storage.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Trash::expiry_interval());
+            loop {
+                interval.tick().await;
+                match expiring_trash.expire(expiring_storage.get_ref()).await {
+                    Ok(0) => {}
+                    Ok(purged) => log::info!("Purged {} expired trash items", purged),
+                    Err(e) => log::error!("Unable to purge the expired trash items: {}", e),
+                }
+            }
+        });
+    }
 
+    // remove the blobs left unreferenced by the replaced files
+    if storage::is_dedup_enabled() {
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(blobs::sweep_interval());
+            loop {
+                interval.tick().await;
+                match web::block(blobs::reclaim).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(removed)) => log::info!("Removed {} unreferenced blobs", removed),
+                    Ok(Err(e)) => log::error!("Unable to remove the unreferenced blobs: {}", e),
+                    Err(e) => log::error!("Unable to remove the unreferenced blobs: {}", e),
+                }
+            }
+        });
+    }
+
+    // remove the finished jobs, and the files they produced, once their retention expires
+    let expiring_jobs = jobs.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Jobs::expiry_interval());
+        loop {
+            interval.tick().await;
+            let jobs = expiring_jobs.clone();
+            if let Err(e) = web::block(move || jobs.expire()).await {
+                log::error!("Unable to remove the expired jobs: {}", e);
+            }
+        }
+    });
+
+    // remove the tus uploads that stopped receiving chunks
+    let expiring_tus_locks = tus_locks.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(tus::expiry_interval());
+        loop {
+            interval.tick().await;
+            let locks = expiring_tus_locks.clone();
+            match web::block(move || tus::expire(&locks)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => log::info!("Removed {} expired tus uploads", removed),
+                Ok(Err(e)) => log::error!("Unable to remove the expired tus uploads: {}", e),
+                Err(e) => log::error!("Unable to remove the expired tus uploads: {}", e),
+            }
+        }
+    });
+
+    let internal_server = HttpServer::new(move || {
         App::new()
+            .app_data(storage.clone())
+            .app_data(auth_config.clone())
+            .app_data(tus_locks.clone())
+            .app_data(versioning.clone())
+            .app_data(trash.clone())
+            .app_data(jobs.clone())
+            .app_data(encodings.clone())
+            .app_data(cache_rules.clone())
             .wrap(middleware::Logger::default())
-            .wrap(keycloak_auth)
+            .wrap(JwtAuth::new(key_store.clone()))
+            .wrap(error::handlers())
             .service(handlers::upload)
             .service(handlers::list)
             .service(handlers::delete)
             .service(utils::compress)
             .service(utils::decompress)
+            .service(utils::compress_job)
+            .service(utils::decompress_job)
+            .service(jobs::list_jobs)
+            .service(jobs::job_status)
+            .service(jobs::job_result)
+            .service(jobs::cancel_job)
+            .service(tus::tus_options)
+            .service(tus::tus_create)
+            .service(tus::tus_head)
+            .service(tus::tus_patch)
+            .service(tus::tus_delete)
+            .service(versions::file_versions)
+            .service(versions::restore_version)
+            .service(deploy::target_releases)
+            .service(deploy::activate_release)
+            .service(trash::list_trash)
+            .service(trash::restore_trash)
+            .service(trash::purge_trash)
+            .service(trash::purge_trash_item)
+            .service(cache::get_cache_rules)
+            .service(cache::put_cache_rules)
+            .service(cache::delete_cache_rules)
             .service(handlers::index_protected)
     })
     .bind(format!("0.0.0.0:{}", INTERNAL_PORT))?
@@ -76,15 +243,29 @@ async fn main() -> std::io::Result<()> {
             .allowed_origin_fn(|origin, _req_head| {
                 origin.as_bytes().ends_with(env::var("CORS_ALLOWED_ORIGIN_END_WITH").as_ref().unwrap().as_bytes())
             })
-            .allowed_methods(vec!["GET"])
+            .allowed_methods(vec!["GET", "HEAD"])
             .allowed_headers(vec![
                 http::header::AUTHORIZATION,
                 http::header::ACCEPT,
                 header::CONTENT_TYPE,
+                header::RANGE,
+                header::IF_RANGE,
+                header::IF_NONE_MATCH,
+                header::IF_MODIFIED_SINCE,
+            ])
+            .expose_headers(vec![
+                header::CONTENT_RANGE,
+                header::CONTENT_DISPOSITION,
+                header::ETAG,
+                header::ACCEPT_RANGES,
             ])
             .max_age(3600);
 
         App::new()
+            .app_data(public_storage.clone())
+            .app_data(public_encodings.clone())
+            .app_data(public_cache_rules.clone())
+            .wrap(error::handlers())
             .wrap(middleware::Logger::exclude(
                 middleware::Logger::default(),
                 "/health/health_check",
@@ -95,7 +276,7 @@ async fn main() -> std::io::Result<()> {
             .wrap(cors)
             .service(handlers::index)
             .default_service(web::to(|req: HttpRequest| match *req.method() {
-                Method::GET => HttpResponse::Ok(),
+                Method::GET | Method::HEAD => HttpResponse::Ok(),
                 Method::POST => HttpResponse::MethodNotAllowed(),
                 _ => HttpResponse::NotFound(),
             }))
@@ -0,0 +1,237 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::Error;
+
+/// This struct defines a path that is guaranteed to stay inside its root directory. Every handler
+/// taking a path from the request must resolve it through `SafePath::resolve`.
+///
+/// # Attributes
+/// * root (PathBuf): the directory the path is confined to, e.g. the tenant root
+/// * relative (PathBuf): the normalized path, relative to `root`
+/// * full (PathBuf): `root` joined with `relative`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafePath {
+    root: PathBuf,
+    relative: PathBuf,
+    full: PathBuf,
+}
+
+impl SafePath {
+    /// This function resolves the given relative path inside `root`. It rejects, with a
+    /// `400 Bad Request` error:
+    /// * absolute paths
+    /// * paths containing `..` segments
+    /// * paths crossing a symlink that points outside `root`
+    ///
+    /// `.` segments and empty segments are ignored, so an empty path resolves to `root` itself.
+    ///
+    /// # Arguments
+    /// * root (&Path): the directory the path must stay inside
+    /// * relative (&str): the path taken from the request
+    ///
+    /// # Returns
+    /// (Result<SafePath, Error>): the resolved path or a `400 Bad Request` error
+    pub fn resolve(root: &Path, relative: &str) -> Result<Self, Error> {
+        let mut normalized = PathBuf::new();
+        for component in Path::new(relative).components() {
+            match component {
+                Component::Normal(segment) => normalized.push(segment),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(invalid_path(relative));
+                }
+            }
+        }
+
+        let full = root.join(&normalized);
+        if !stays_inside(root, &full) {
+            return Err(invalid_path(relative));
+        }
+
+        Ok(SafePath {
+            root: root.to_path_buf(),
+            relative: normalized,
+            full,
+        })
+    }
+
+    /// This function resolves another relative path inside this one, keeping the same root.
+    ///
+    /// # Arguments
+    /// * relative (&str): the path to append
+    ///
+    /// # Returns
+    /// (Result<SafePath, Error>): the resolved path or a `400 Bad Request` error
+    pub fn join(&self, relative: &str) -> Result<Self, Error> {
+        let joined = SafePath::resolve(&self.full, relative)?;
+        Ok(SafePath {
+            root: self.root.clone(),
+            relative: self.relative.join(&joined.relative),
+            full: joined.full,
+        })
+    }
+
+    /// The directory the path is confined to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The normalized path, relative to the root
+    pub fn relative(&self) -> &Path {
+        &self.relative
+    }
+}
+
+impl Deref for SafePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.full
+    }
+}
+
+impl AsRef<Path> for SafePath {
+    fn as_ref(&self) -> &Path {
+        &self.full
+    }
+}
+
+impl fmt::Display for SafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.full.display())
+    }
+}
+
+fn invalid_path(path: &str) -> Error {
+    Error::BadRequest(format!("Invalid path: {}", path))
+}
+
+/// Symlinks are followed on the deepest existing ancestor of `full`, which must still be inside
+/// `root` once canonicalized. When the root does not exist yet there is nothing to follow.
+fn stays_inside(root: &Path, full: &Path) -> bool {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return true,
+    };
+    let existing = full.ancestors().find(|ancestor| ancestor.symlink_metadata().is_ok());
+    match existing.map(|ancestor| ancestor.canonicalize()) {
+        Some(Ok(canonical)) => canonical.starts_with(&root),
+        // a dangling symlink cannot be checked
+        Some(Err(_)) => false,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use uuid::Uuid;
+
+    /// This function creates a new root, with an `inside` directory and a sibling `outside`
+    /// directory.
+    fn root() -> PathBuf {
+        let base = env::temp_dir().join(format!("cds-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(base.join("root/inside")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        base.join("root")
+    }
+
+    fn is_rejected(result: Result<SafePath, Error>) -> bool {
+        matches!(result, Err(Error::BadRequest(_)))
+    }
+
+    #[test]
+    fn rejects_the_parent_segments() {
+        let root = root();
+        let relatives = ["..", "../outside", "inside/../..", "inside/../inside", "./..", "a/./../b"];
+        for relative in relatives {
+            assert!(is_rejected(SafePath::resolve(&root, relative)), "{}", relative);
+        }
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_absolute_paths() {
+        let root = root();
+        for relative in ["/", "/etc/passwd", &root.join("inside").display().to_string()] {
+            assert!(is_rejected(SafePath::resolve(&root, relative)), "{}", relative);
+        }
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn normalizes_the_current_and_empty_segments() {
+        let root = root();
+        let empty = SafePath::resolve(&root, "").unwrap();
+        assert_eq!(empty.relative(), Path::new(""));
+        assert_eq!(&*empty, root.as_path());
+
+        let path = SafePath::resolve(&root, "./inside//./file.txt/").unwrap();
+        assert_eq!(path.relative(), Path::new("inside/file.txt"));
+        assert_eq!(&*path, root.join("inside/file.txt").as_path());
+        assert_eq!(path.root(), root.as_path());
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_symlinks_pointing_outside() {
+        let root = root();
+        symlink(root.parent().unwrap().join("outside"), root.join("escape")).unwrap();
+        symlink(root.join("inside"), root.join("alias")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        assert!(is_rejected(SafePath::resolve(&root, "escape")));
+        assert!(is_rejected(SafePath::resolve(&root, "escape/file.txt")));
+        assert!(is_rejected(SafePath::resolve(&root, "escape/new/file.txt")));
+        assert!(is_rejected(SafePath::resolve(&root, "dangling/file.txt")));
+        // a symlink staying inside the root is followed
+        assert!(SafePath::resolve(&root, "alias/file.txt").is_ok());
+
+        let inside = SafePath::resolve(&root, "inside").unwrap();
+        assert!(is_rejected(inside.join("../escape")));
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn accepts_the_paths_not_created_yet() {
+        let root = root();
+        let path = SafePath::resolve(&root, "new/directory/file.txt").unwrap();
+        assert_eq!(path.relative(), Path::new("new/directory/file.txt"));
+
+        let joined = SafePath::resolve(&root, "inside").unwrap().join("new/file.txt").unwrap();
+        assert_eq!(joined.relative(), Path::new("inside/new/file.txt"));
+        assert_eq!(joined.root(), root.as_path());
+
+        // the root itself may not exist yet, e.g. for a new tenant
+        let missing = root.join("missing-root");
+        assert!(SafePath::resolve(&missing, "public/file.txt").is_ok());
+        assert!(is_rejected(SafePath::resolve(&missing, "../file.txt")));
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,668 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+//! The files of the tenants are served with the conditional requests (`If-Match`,
+//! `If-None-Match`, `If-Modified-Since`, `If-Unmodified-Since`) and the range requests (`Range`,
+//! `If-Range`, multipart byte ranges), for both the storage backends. The compressible files are
+//! also served precompressed or compressed on the fly, following the `Accept-Encoding` header.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::body::SizedStream;
+use actix_web::http::header::{
+    self, Charset, ContentDisposition, DispositionParam, DispositionType, EntityTag,
+    ExtendedValue, HttpDate,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures::stream::{self, StreamExt};
+use http_range::{HttpRange, HttpRangeParseError};
+use mime_guess::mime::{self, Mime};
+use uuid::Uuid;
+
+use crate::encoding::{self, Encoding, Encodings};
+use crate::error::Error;
+use crate::storage::{ObjectMeta, ObjectStream, Storage};
+
+/// The most ranges served in a single response, more are answered with the whole file
+const MAX_RANGES: usize = 16;
+
+/// This enum defines how a request is answered once its preconditions have passed
+enum Ranges {
+    /// the whole file, with `200 OK`
+    Full,
+    /// the requested ranges, with `206 Partial Content`
+    Partial(Vec<HttpRange>),
+    /// no range overlaps the file, `416 Range Not Satisfiable`
+    Unsatisfiable,
+}
+
+/// This struct defines the representation of a file sent to the client: the file itself or one of
+/// its precompressed siblings.
+///
+/// # Attributes
+/// * path (&Path): the requested file, naming the content type and the disposition
+/// * object (PathBuf): the stored file sent in the body
+/// * meta (ObjectMeta): the metadata of `object`
+/// * encoding (Option<Encoding>): the content coding of `object`, `None` for the file itself
+/// * vary (bool): whether the representation depends on the `Accept-Encoding` header
+struct Representation<'a> {
+    path: &'a Path,
+    object: PathBuf,
+    meta: ObjectMeta,
+    encoding: Option<Encoding>,
+    vary: bool,
+}
+
+/// This function returns the content of a stored file, or only its headers for a `HEAD` request.
+/// The `ETag` and the `Last-Modified` headers are used to answer the conditional requests, with
+/// `304 Not Modified` or `412 Precondition Failed`. The `Range` requests are answered with
+/// `206 Partial Content`, one range in the body or several ones in a `multipart/byteranges` body,
+/// unless an `If-Range` header no longer matches the file.
+///
+/// # Arguments
+/// * storage (&dyn Storage): the storage backend
+/// * req (&HttpRequest): the request, for its method and its conditional and range headers
+/// * path (&Path): the file to serve
+///
+/// # Returns
+/// (Result<HttpResponse, Error>): the file, or a `404 Not Found` if it does not exist
+pub async fn serve_file(
+    storage: &dyn Storage,
+    req: &HttpRequest,
+    path: &Path,
+) -> Result<HttpResponse, Error> {
+    let meta = stat_file(storage, path).await?;
+    let representation = Representation {
+        path,
+        object: path.to_path_buf(),
+        meta,
+        encoding: None,
+        vary: false,
+    };
+    serve_representation(storage, req, representation).await
+}
+
+/// This function returns a stored file like `serve_file`, honoring the `Accept-Encoding` header
+/// for the compressible types: the `.br` or `.gz` sibling of the file is sent when it exists (the
+/// siblings are replaced whenever the file is stored), otherwise the file is compressed on the fly
+/// when `CDS_COMPRESS` is enabled.
+///
+/// # Arguments
+/// * storage (&dyn Storage): the storage backend
+/// * encodings (&Encodings): the compression settings
+/// * req (&HttpRequest): the request, for its method and its conditional, range and
+///   `Accept-Encoding` headers
+/// * path (&Path): the file to serve
+///
+/// # Returns
+/// (Result<HttpResponse, Error>): the file, or a `404 Not Found` if it does not exist
+pub async fn serve_encoded(
+    storage: &dyn Storage,
+    encodings: &Encodings,
+    req: &HttpRequest,
+    path: &Path,
+) -> Result<HttpResponse, Error> {
+    let meta = stat_file(storage, path).await?;
+    let compressible = encoding::compressible(&content_type(path));
+    if compressible {
+        let accepted = encoding::accepted(req);
+        for encoding in &accepted {
+            let sibling = encoding.sibling(path);
+            match storage.stat(&sibling).await? {
+                Some(sibling_meta) if !sibling_meta.is_dir => {
+                    let representation = Representation {
+                        path,
+                        object: sibling,
+                        meta: sibling_meta,
+                        encoding: Some(*encoding),
+                        vary: true,
+                    };
+                    return serve_representation(storage, req, representation).await;
+                }
+                _ => {}
+            }
+        }
+        if let Some(encoding) = accepted.first().filter(|_| encodings.compresses(meta.size)) {
+            return serve_compressed(storage, req, path, meta, *encoding).await;
+        }
+    }
+
+    let representation = Representation {
+        path,
+        object: path.to_path_buf(),
+        meta,
+        encoding: None,
+        vary: compressible,
+    };
+    serve_representation(storage, req, representation).await
+}
+
+/// This function returns the metadata of the file to serve, a `404 Not Found` if it does not exist
+/// or it is a directory.
+async fn stat_file(storage: &dyn Storage, path: &Path) -> Result<ObjectMeta, Error> {
+    match storage.stat(path).await? {
+        Some(meta) if !meta.is_dir => Ok(meta),
+        _ => Err(Error::NotFound(
+            "File not found. Or tried to list content of a directory.".to_string(),
+        )),
+    }
+}
+
+/// This function answers the conditional and range requests on a representation of the file.
+async fn serve_representation(
+    storage: &dyn Storage,
+    req: &HttpRequest,
+    representation: Representation<'_>,
+) -> Result<HttpResponse, Error> {
+    let meta = &representation.meta;
+    let object = representation.object.as_path();
+    let etag = entity_tag(meta);
+    let content_type = content_type(representation.path);
+
+    if let Some(res) = check_preconditions(req, &etag, meta.modified, representation.vary) {
+        return Ok(res);
+    }
+
+    let mut res = HttpResponse::Ok();
+    representation_headers(&mut res, representation.path, &content_type, representation.vary)
+        .insert_header((header::ETAG, etag.to_string()))
+        .insert_header((header::LAST_MODIFIED, HttpDate::from(meta.modified).to_string()))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    if let Some(encoding) = representation.encoding {
+        res.insert_header((header::CONTENT_ENCODING, encoding.token()));
+    }
+
+    let head = req.method() == Method::HEAD;
+    match requested_ranges(req, &etag, meta.modified, meta.size) {
+        Ranges::Full => {
+            let body = if head {
+                empty_stream()
+            } else {
+                storage.get(object).await?
+            };
+            Ok(res.body(SizedStream::new(meta.size, body)))
+        }
+        Ranges::Unsatisfiable => Ok(HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", meta.size)))
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .finish()),
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .insert_header((header::CONTENT_RANGE, content_range(&range, meta.size)));
+            let body = if head {
+                empty_stream()
+            } else {
+                storage.get_range(object, range.start, range.length).await?
+            };
+            Ok(res.body(SizedStream::new(range.length, body)))
+        }
+        Ranges::Partial(ranges) => {
+            multipart_ranges(storage, res, object, &content_type, &ranges, meta.size, head).await
+        }
+    }
+}
+
+/// This function returns a file compressed on the fly. Its entity tag is weak, as the compressed
+/// content is not byte for byte stable, and the `Range` requests are answered with the whole
+/// compressed content.
+async fn serve_compressed(
+    storage: &dyn Storage,
+    req: &HttpRequest,
+    path: &Path,
+    meta: ObjectMeta,
+    encoding: Encoding,
+) -> Result<HttpResponse, Error> {
+    let etag = EntityTag::new_weak(format!("{}-{}", entity_tag(&meta).tag(), encoding.token()));
+    if let Some(res) = check_preconditions(req, &etag, meta.modified, true) {
+        return Ok(res);
+    }
+
+    let content = encoding::read_content(storage, path).await?;
+    let compressed = web::block(move || encoding.compress(&content, false)).await??;
+
+    let mut res = HttpResponse::Ok();
+    representation_headers(&mut res, path, &content_type(path), true)
+        .insert_header((header::CONTENT_ENCODING, encoding.token()))
+        .insert_header((header::ETAG, etag.to_string()))
+        .insert_header((header::LAST_MODIFIED, HttpDate::from(meta.modified).to_string()));
+    if req.method() == Method::HEAD {
+        return Ok(res.body(SizedStream::new(compressed.len() as u64, empty_stream())));
+    }
+    Ok(res.body(compressed))
+}
+
+/// The headers describing the requested file, whatever representation of it is sent.
+fn representation_headers<'a>(
+    res: &'a mut HttpResponseBuilder,
+    path: &Path,
+    content_type: &Mime,
+    vary: bool,
+) -> &'a mut HttpResponseBuilder {
+    res.insert_header((header::CONTENT_TYPE, content_type.to_string()))
+        .insert_header((header::CONTENT_DISPOSITION, content_disposition(path, content_type)));
+    if vary {
+        res.insert_header((header::VARY, "Accept-Encoding"));
+    }
+    res
+}
+
+/// This function returns the `multipart/byteranges` response of several ranges: every part has the
+/// `Content-Type` of the file and its own `Content-Range`.
+async fn multipart_ranges(
+    storage: &dyn Storage,
+    mut res: HttpResponseBuilder,
+    path: &Path,
+    content_type: &Mime,
+    ranges: &[HttpRange],
+    size: u64,
+    head: bool,
+) -> Result<HttpResponse, Error> {
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut parts: Vec<ObjectStream> = vec![];
+    let mut length = 0;
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            content_range(range, size)
+        );
+        length += part_header.len() as u64 + range.length;
+        if !head {
+            parts.push(bytes_stream(part_header));
+            parts.push(storage.get_range(path, range.start, range.length).await?);
+        }
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    length += closing.len() as u64;
+    parts.push(bytes_stream(closing));
+
+    let body: ObjectStream = if head {
+        empty_stream()
+    } else {
+        Box::pin(stream::iter(parts).flatten())
+    };
+    res.status(StatusCode::PARTIAL_CONTENT).insert_header((
+        header::CONTENT_TYPE,
+        format!("multipart/byteranges; boundary={}", boundary),
+    ));
+    Ok(res.body(SizedStream::new(length, body)))
+}
+
+/// The entity tag combines the size and the modification time with the revision of the backend,
+/// so that a file replaced with the same size and modification time (as an extracted archive
+/// restores them) still gets a different tag. Without a revision the tag is weak, so that it is
+/// never used to combine the ranges of two different contents.
+fn entity_tag(meta: &ObjectMeta) -> EntityTag {
+    let modified = meta.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut tag = format!(
+        "{:x}:{:x}:{:x}",
+        meta.size,
+        modified.as_secs(),
+        modified.subsec_nanos()
+    );
+    match &meta.revision {
+        Some(revision) => {
+            tag.push(':');
+            tag.extend(revision.chars().filter(|c| c.is_ascii_graphic() && *c != '"'));
+            EntityTag::new_strong(tag)
+        }
+        None => EntityTag::new_weak(tag),
+    }
+}
+
+/// The dates of the HTTP headers have a resolution of one second.
+fn same_second(a: SystemTime, b: SystemTime) -> bool {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .ok()
+    };
+    secs(a) == secs(b)
+}
+
+fn newer_than(modified: SystemTime, since: SystemTime) -> bool {
+    !same_second(modified, since) && modified > since
+}
+
+/// This function evaluates the preconditions in the order of RFC 9110: `If-Match`, or else
+/// `If-Unmodified-Since`, then `If-None-Match`, or else `If-Modified-Since`.
+///
+/// # Returns
+/// (Option<HttpResponse>): `412 Precondition Failed` or `304 Not Modified`, `None` to serve the
+/// file
+fn check_preconditions(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    modified: SystemTime,
+    vary: bool,
+) -> Option<HttpResponse> {
+    let precondition_failed = match req.get_header::<header::IfMatch>() {
+        Some(header::IfMatch::Any) => false,
+        Some(header::IfMatch::Items(items)) => !items.iter().any(|item| item.strong_eq(etag)),
+        None => match req.get_header::<header::IfUnmodifiedSince>() {
+            Some(header::IfUnmodifiedSince(since)) => newer_than(modified, since.into()),
+            None => false,
+        },
+    };
+    if precondition_failed {
+        return Some(HttpResponse::PreconditionFailed().finish());
+    }
+
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => match req.get_header::<header::IfModifiedSince>() {
+            Some(header::IfModifiedSince(since)) => !newer_than(modified, since.into()),
+            None => false,
+        },
+    };
+    if !not_modified {
+        return None;
+    }
+    let mut res = HttpResponse::NotModified();
+    res.insert_header((header::ETAG, etag.to_string()))
+        .insert_header((header::LAST_MODIFIED, HttpDate::from(modified).to_string()));
+    if vary {
+        res.insert_header((header::VARY, "Accept-Encoding"));
+    }
+    Some(res.finish())
+}
+
+/// This function returns the ranges to serve. The `Range` header is ignored, and the whole file
+/// served, when it cannot be parsed, when an `If-Range` header does not match the current version
+/// of the file, or when it asks for too many ranges or for more bytes than the file has.
+fn requested_ranges(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    modified: SystemTime,
+    size: u64,
+) -> Ranges {
+    let range = match req.headers().get(header::RANGE).and_then(|value| value.to_str().ok()) {
+        Some(range) => range,
+        None => return Ranges::Full,
+    };
+    if let Some(if_range) = req.get_header::<header::IfRange>() {
+        let matches = match if_range {
+            header::IfRange::EntityTag(tag) => tag.strong_eq(etag),
+            header::IfRange::Date(date) => same_second(date.into(), modified),
+        };
+        if !matches {
+            return Ranges::Full;
+        }
+    }
+
+    match HttpRange::parse(range, size) {
+        Ok(ranges) if ranges.is_empty() || ranges.len() > MAX_RANGES => Ranges::Full,
+        Ok(ranges) if ranges.iter().map(|range| range.length).sum::<u64>() > size => Ranges::Full,
+        Ok(ranges) => Ranges::Partial(ranges),
+        Err(HttpRangeParseError::NoOverlap) => Ranges::Unsatisfiable,
+        Err(HttpRangeParseError::InvalidRange) => Ranges::Full,
+    }
+}
+
+fn content_range(range: &HttpRange, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.start + range.length - 1, size)
+}
+
+/// The content type is guessed from the extension, the text types are served as UTF-8.
+pub fn content_type(path: &Path) -> Mime {
+    let guessed = mime_guess::from_path(path).first_or_octet_stream();
+    match (guessed.type_(), guessed.subtype()) {
+        (mime::APPLICATION, mime::JAVASCRIPT) => mime::APPLICATION_JAVASCRIPT_UTF_8,
+        (mime::TEXT, mime::JAVASCRIPT) => "text/javascript; charset=utf-8".parse().unwrap(),
+        (mime::TEXT, mime::HTML) => mime::TEXT_HTML_UTF_8,
+        (mime::TEXT, mime::CSS) => mime::TEXT_CSS_UTF_8,
+        (mime::TEXT, mime::PLAIN) => mime::TEXT_PLAIN_UTF_8,
+        (mime::TEXT, mime::CSV) => mime::TEXT_CSV_UTF_8,
+        _ => guessed,
+    }
+}
+
+/// The media the browsers can display are served `inline`, the others as an `attachment`.
+fn content_disposition(path: &Path, content_type: &Mime) -> ContentDisposition {
+    let disposition = match (content_type.type_(), content_type.subtype()) {
+        (mime::IMAGE | mime::TEXT | mime::AUDIO | mime::VIDEO, _) => DispositionType::Inline,
+        (mime::APPLICATION, mime::JAVASCRIPT | mime::JSON) => DispositionType::Inline,
+        (mime::APPLICATION, subtype) if subtype == "wasm" || subtype == "xhtml" => {
+            DispositionType::Inline
+        }
+        _ => DispositionType::Attachment,
+    };
+    let filename = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let mut parameters = vec![DispositionParam::Filename(
+        filename.replace(['\r', '\n'], "_"),
+    )];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.into_bytes(),
+        }));
+    }
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+}
+
+fn bytes_stream(bytes: String) -> ObjectStream {
+    Box::pin(stream::once(async move { Ok(Bytes::from(bytes)) }))
+}
+
+fn empty_stream() -> ObjectStream {
+    Box::pin(stream::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::body::{self, BodySize, MessageBody};
+    use actix_web::http::header::HeaderMap;
+    use actix_web::test::TestRequest;
+
+    use crate::storage;
+
+    const CONTENT: &str = "0123456789";
+
+    struct Served {
+        status: StatusCode,
+        headers: HeaderMap,
+        size: BodySize,
+        body: String,
+    }
+
+    /// This function writes `CONTENT` in a new directory and returns the storage and its path.
+    fn stored_file() -> (Arc<dyn Storage>, PathBuf) {
+        let root = env::temp_dir().join(format!("cds-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("file.txt");
+        fs::write(&path, CONTENT).unwrap();
+        (storage::from_env().unwrap(), path)
+    }
+
+    async fn serve(
+        storage: &dyn Storage,
+        path: &Path,
+        method: Method,
+        headers: &[(header::HeaderName, &str)],
+    ) -> Served {
+        let mut req = TestRequest::default().method(method);
+        for (name, value) in headers {
+            req = req.insert_header((name.clone(), *value));
+        }
+        let res = serve_file(storage, &req.to_http_request(), path).await.unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let size = res.body().size();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        Served {
+            status,
+            headers,
+            size,
+            body: String::from_utf8_lossy(&body).to_string(),
+        }
+    }
+
+    fn header_of(served: &Served, name: header::HeaderName) -> String {
+        served.headers.get(name).unwrap().to_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn serves_the_single_ranges() {
+        let (storage, path) = stored_file();
+        for (range, body, content_range) in [
+            ("bytes=2-5", "2345", "bytes 2-5/10"),
+            ("bytes=-3", "789", "bytes 7-9/10"),
+            ("bytes=5-", "56789", "bytes 5-9/10"),
+            ("bytes=8-20", "89", "bytes 8-9/10"),
+        ] {
+            let served = serve(&*storage, &path, Method::GET, &[(header::RANGE, range)]).await;
+            assert_eq!(served.status, StatusCode::PARTIAL_CONTENT, "{}", range);
+            assert_eq!(served.body, body, "{}", range);
+            assert_eq!(header_of(&served, header::CONTENT_RANGE), content_range);
+            assert_eq!(served.size, BodySize::Sized(body.len() as u64));
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn serves_the_multiple_ranges() {
+        let (storage, path) = stored_file();
+        let served = serve(&*storage, &path, Method::GET, &[(header::RANGE, "bytes=0-1,-2")]).await;
+        assert_eq!(served.status, StatusCode::PARTIAL_CONTENT);
+        let content_type = header_of(&served, header::CONTENT_TYPE);
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\
+             \r\n\r\n01\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: \
+             bytes 8-9/10\r\n\r\n89\r\n--{0}--\r\n",
+            boundary
+        );
+        assert_eq!(served.body, expected);
+        assert_eq!(served.size, BodySize::Sized(expected.len() as u64));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_the_unsatisfiable_ranges() {
+        let (storage, path) = stored_file();
+        let served = serve(&*storage, &path, Method::GET, &[(header::RANGE, "bytes=10-")]).await;
+        assert_eq!(served.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header_of(&served, header::CONTENT_RANGE), "bytes */10");
+        assert_eq!(served.body, "");
+
+        let served = serve(&*storage, &path, Method::GET, &[(header::RANGE, "lines=1-2")]).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.body, CONTENT);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn serves_the_ranges_of_the_same_version_only() {
+        let (storage, path) = stored_file();
+        let served = serve(&*storage, &path, Method::GET, &[]).await;
+        let etag = header_of(&served, header::ETAG);
+        let last_modified = header_of(&served, header::LAST_MODIFIED);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let earlier = HttpDate::from(modified - Duration::from_secs(3600)).to_string();
+
+        for (if_range, status, body) in [
+            (etag.as_str(), StatusCode::PARTIAL_CONTENT, "2345"),
+            ("\"stale\"", StatusCode::OK, CONTENT),
+            (&format!("W/{}", etag), StatusCode::OK, CONTENT),
+            (last_modified.as_str(), StatusCode::PARTIAL_CONTENT, "2345"),
+            (earlier.as_str(), StatusCode::OK, CONTENT),
+        ] {
+            let headers = [(header::RANGE, "bytes=2-5"), (header::IF_RANGE, if_range)];
+            let served = serve(&*storage, &path, Method::GET, &headers).await;
+            assert_eq!(served.status, status, "{}", if_range);
+            assert_eq!(served.body, body, "{}", if_range);
+        }
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn changes_the_entity_tag_when_the_file_is_replaced() {
+        let (storage, path) = stored_file();
+        let served = serve(&*storage, &path, Method::GET, &[]).await;
+        let etag = header_of(&served, header::ETAG);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        // same size and modification time, as restored by an archive
+        let replacement = path.with_file_name("replacement.txt");
+        fs::write(&replacement, "abcdefghij").unwrap();
+        File::options()
+            .write(true)
+            .open(&replacement)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::rename(&replacement, &path).unwrap();
+
+        let headers = [(header::RANGE, "bytes=2-5"), (header::IF_RANGE, etag.as_str())];
+        let served = serve(&*storage, &path, Method::GET, &headers).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.body, "abcdefghij");
+        assert_ne!(header_of(&served, header::ETAG), etag);
+
+        let served = serve(&*storage, &path, Method::GET, &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(served.status, StatusCode::OK);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn answers_the_head_requests_without_a_body() {
+        let (storage, path) = stored_file();
+        let served = serve(&*storage, &path, Method::HEAD, &[]).await;
+        assert_eq!(served.status, StatusCode::OK);
+        assert_eq!(served.size, BodySize::Sized(10));
+        assert_eq!(served.body, "");
+        assert_eq!(header_of(&served, header::ACCEPT_RANGES), "bytes");
+        assert!(served.headers.contains_key(header::ETAG));
+
+        let served = serve(&*storage, &path, Method::HEAD, &[(header::RANGE, "bytes=2-5")]).await;
+        assert_eq!(served.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(served.size, BodySize::Sized(4));
+        assert_eq!(served.body, "");
+        assert_eq!(header_of(&served, header::CONTENT_RANGE), "bytes 2-5/10");
+
+        let headers = [(header::RANGE, "bytes=0-1,4-5")];
+        let served = serve(&*storage, &path, Method::HEAD, &headers).await;
+        assert_eq!(served.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(served.body, "");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use actix_multipart::Field;
+use actix_web::web;
+use futures::{Stream, TryStreamExt};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::storage::Storage;
+use crate::tenant::BASE_PATH;
+
+/// The staging area lives on the same volume as the tenant roots, so a spooled file can be moved
+/// into place with a rename.
+pub const STAGING_PATH: &str = ".staging";
+
+/// The prefix of the temporary files written next to their final path. They are never listed nor
+/// served and the ones left behind by a crash are removed on startup.
+pub const TEMP_PREFIX: &str = ".cds-tmp-";
+
+/// The resumable uploads in the staging area survive a restart.
+const KEPT_STAGING_ENTRIES: [&str; 1] = ["tus"];
+
+/// This struct defines a file part of a multipart request, written to the staging area until
+/// all the other fields of the request are known. The file, if still in the staging area, is
+/// removed when the struct is dropped.
+///
+/// # Attributes
+/// * path (PathBuf): the path of the spooled file inside the staging area
+/// * sha256 (String): the hex encoded SHA-256 digest of the content
+pub struct SpooledFile {
+    path: PathBuf,
+    sha256: String,
+}
+
+impl SpooledFile {
+    /// This function streams the given multipart field into a new file in the staging area,
+    /// computing its digest on the way.
+    ///
+    /// # Arguments
+    /// * field (&mut Field): the multipart file field
+    ///
+    /// # Returns
+    /// (Result<SpooledFile, Error>): the spooled file
+    pub async fn from_field(field: &mut Field) -> Result<Self, Error> {
+        Self::from_stream(field).await
+    }
+
+    /// This function copies the file at `source` to a new file in the staging area, so it can be
+    /// stored again as a new file.
+    ///
+    /// # Arguments
+    /// * storage (&dyn Storage): the storage backend
+    /// * source (&Path): the file to copy
+    ///
+    /// # Returns
+    /// (Result<SpooledFile, Error>): the spooled file
+    pub async fn from_storage(storage: &dyn Storage, source: &Path) -> Result<Self, Error> {
+        Self::from_stream(storage.get(source).await?).await
+    }
+
+    async fn from_stream<E>(
+        mut chunks: impl Stream<Item = Result<web::Bytes, E>> + Unpin,
+    ) -> Result<Self, Error>
+    where
+        Error: From<E>,
+    {
+        let staging = Path::new(BASE_PATH).join(STAGING_PATH);
+        let path = staging.join(Uuid::new_v4().to_string());
+        let file_path = path.clone();
+        let mut f = web::block(move || {
+            fs::create_dir_all(staging)?;
+            fs::File::create(file_path)
+        })
+        .await??;
+
+        // the file is removed if the stream fails before the end
+        let mut spooled = SpooledFile {
+            path,
+            sha256: "".to_string(),
+        };
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = chunks.try_next().await? {
+            hasher.update(&chunk);
+            f = web::block(move || f.write_all(&chunk).map(|_| f)).await??;
+        }
+        web::block(move || f.sync_all()).await??;
+        spooled.sha256 = hex::encode(hasher.finalize());
+        Ok(spooled)
+    }
+
+    /// This function writes the given content to a new file in the staging area.
+    ///
+    /// # Arguments
+    /// * content (Vec<u8>): the content of the file
+    ///
+    /// # Returns
+    /// (Result<SpooledFile, Error>): the spooled file
+    pub async fn from_bytes(content: Vec<u8>) -> Result<Self, Error> {
+        let staging = Path::new(BASE_PATH).join(STAGING_PATH);
+        let path = staging.join(Uuid::new_v4().to_string());
+        let spooled = SpooledFile {
+            path: path.clone(),
+            sha256: hex::encode(Sha256::digest(&content)),
+        };
+        web::block(move || {
+            fs::create_dir_all(staging)?;
+            let mut f = fs::File::create(path)?;
+            f.write_all(&content)?;
+            f.sync_all()
+        })
+        .await??;
+        Ok(spooled)
+    }
+
+    /// The hex encoded SHA-256 digest of the content
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// The path of the spooled file inside the staging area
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// This function stores the spooled file at the given path, replacing any existing file.
+    ///
+    /// # Arguments
+    /// * storage (&dyn Storage): the storage backend
+    /// * target (&Path): the final path of the file
+    ///
+    /// # Returns
+    /// (Result<(), Error>): an error if the file cannot be stored
+    pub async fn persist(self, storage: &dyn Storage, target: &Path) -> Result<(), Error> {
+        storage.put(target, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// This function atomically replaces `target` with the fully written `source` file, so readers
+/// see either the previous content or the new one, never a partial file. When `source` is on
+/// another volume it is first copied to a temporary file next to `target` and then renamed.
+///
+/// # Arguments
+/// * source (&Path): the file to move, already synced to disk
+/// * target (&Path): the final path of the file
+///
+/// # Returns
+/// (io::Result<()>): an error if the file cannot be moved
+pub fn persist_file(source: &Path, target: &Path) -> io::Result<()> {
+    match fs::rename(source, target) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let temp = temp_path(target);
+            let copied = fs::copy(source, &temp)
+                .and_then(|_| fs::File::open(&temp)?.sync_all())
+                .and_then(|_| fs::rename(&temp, target));
+            if copied.is_err() {
+                let _ = fs::remove_file(&temp);
+            }
+            copied?;
+            fs::remove_file(source)?;
+        }
+        Err(err) => return Err(err),
+    }
+    sync_parent(target)
+}
+
+/// This function returns a unique temporary path in the same directory of `target`.
+pub fn temp_path(target: &Path) -> PathBuf {
+    let name = format!("{}{}", TEMP_PREFIX, Uuid::new_v4());
+    match target.parent() {
+        Some(parent) => parent.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// The rename is durable only once the directory entry is on disk.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => fs::File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// This function removes what an interrupted write left behind: the spooled files and the staging
+/// directories in the staging area and the temporary files in the tenant directories. It must run
+/// before serving requests.
+///
+/// # Returns
+/// (usize): the number of removed files
+pub fn cleanup() -> usize {
+    let base = Path::new(BASE_PATH);
+    let staging = base.join(STAGING_PATH);
+    let mut removed = 0;
+    if let Ok(entries) = fs::read_dir(&staging) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if KEPT_STAGING_ENTRIES.iter().any(|kept| name == *kept) {
+                continue;
+            }
+            let path = entry.path();
+            let cleared = match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => fs::remove_dir_all(&path),
+                _ => fs::remove_file(&path),
+            };
+            if cleared.is_ok() {
+                removed += 1;
+            }
+        }
+    }
+    remove_temp_files(base, &staging, &mut removed);
+    removed
+}
+
+fn remove_temp_files(dir: &Path, staging: &Path, removed: &mut usize) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        // symlinks are not followed, they could point outside the data directory
+        if file_type.is_dir() {
+            if path != staging {
+                remove_temp_files(&path, staging, removed);
+            }
+        } else if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX)
+            && fs::remove_file(&path).is_ok()
+        {
+            *removed += 1;
+        }
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// This struct defines a directory in the staging area, where the files of a backend without a
+/// `local_path` are processed, e.g. an archive extracted before its files are stored. The
+/// directory is removed with its content when the struct is dropped.
+///
+/// # Attributes
+/// * path (PathBuf): the path of the directory inside the staging area
+pub struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    /// This function creates a new empty directory in the staging area.
+    ///
+    /// # Returns
+    /// (io::Result<StagingDir>): the staging directory
+    pub fn new() -> io::Result<Self> {
+        let path = Path::new(BASE_PATH)
+            .join(STAGING_PATH)
+            .join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&path)?;
+        Ok(StagingDir { path })
+    }
+
+    /// The path of the directory inside the staging area
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,344 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::env;
+use std::fs;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures::{Stream, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::blobs;
+use crate::error::Error;
+use crate::s3::S3Storage;
+use crate::spool::{persist_file, temp_path, TEMP_PREFIX};
+use crate::tenant::Tenant;
+
+/// The content of a stored file, streamed in chunks
+pub type ObjectStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>>>>;
+
+/// This struct defines the metadata of a stored file or directory
+///
+/// # Attributes
+/// * name (String): the last segment of the path
+/// * path (PathBuf): the path, starting with `entando-data`
+/// * size (u64): the size in bytes, `0` for the directories of an object store
+/// * modified (SystemTime): the last modification time
+/// * is_dir (bool): `true` for a directory
+/// * revision (Option<String>): identifies the stored content, `None` if the backend cannot tell:
+///   the device, inode and change time of a file on the filesystem, the ETag of an object
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub is_dir: bool,
+    pub revision: Option<String>,
+}
+
+/// This trait defines where the tenant files are stored. Every path starts with `entando-data`, as
+/// returned by the `Tenant` functions, and must have been resolved through `SafePath`.
+///
+/// The staging area (spooled uploads and resumable uploads) is always on the local filesystem.
+/// The archive utilities process the files in place with a backend having a `local_path`, and copy
+/// them to the staging area with the other ones. The releases switch a local symlink, they need a
+/// backend with a `local_path`, see `require_local_storage`.
+#[async_trait(?Send)]
+pub trait Storage: Send + Sync {
+    /// This function stores the content of the local `source` file at `path`, replacing any
+    /// existing file. The content is visible only once it has been completely stored.
+    async fn put(&self, path: &Path, source: &Path) -> io::Result<()>;
+
+    /// This function returns the content of the file at `path`.
+    async fn get(&self, path: &Path) -> io::Result<ObjectStream>;
+
+    /// This function returns `length` bytes of the file at `path`, starting at `offset`.
+    async fn get_range(&self, path: &Path, offset: u64, length: u64) -> io::Result<ObjectStream>;
+
+    /// This function returns the metadata of the file or directory at `path`, `None` if it does not
+    /// exist.
+    async fn stat(&self, path: &Path) -> io::Result<Option<ObjectMeta>>;
+
+    /// This function returns the direct children of the directory at `path`.
+    async fn list(&self, path: &Path) -> io::Result<Vec<ObjectMeta>>;
+
+    /// This function creates the directory at `path` and its parents.
+    async fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// This function removes the file at `path`, or the directory with all its content.
+    async fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// This function copies the file at `from` to `to`, replacing any existing file.
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// This function moves the file or directory at `from` to `to`.
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// This function takes over the file written directly on the local filesystem at `path`, e.g.
+    /// extracted by `decompress`, as if it had been stored with `put`.
+    async fn adopt(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// This function returns the local path of `path` for the backends storing files on the local
+    /// filesystem, so they can be processed in place.
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// This function creates the storage backend selected by the `CDS_STORAGE` env var: `filesystem`
+/// (the default) or `s3`. With `CDS_STORAGE_DEDUP=true` the filesystem storage deduplicates the
+/// files by content.
+///
+/// # Returns
+/// (Result<Arc<dyn Storage>, String>): the storage backend or the configuration error
+pub fn from_env() -> Result<Arc<dyn Storage>, String> {
+    let dedup = is_dedup_enabled();
+    match env::var("CDS_STORAGE").unwrap_or_default().as_str() {
+        "" | "filesystem" => Ok(Arc::new(FilesystemStorage { dedup })),
+        "s3" if dedup => Err("CDS_STORAGE_DEDUP needs the filesystem storage".to_string()),
+        "s3" => Ok(Arc::new(S3Storage::from_env()?)),
+        other => Err(format!("Unknown CDS_STORAGE backend: {}", other)),
+    }
+}
+
+/// The files are deduplicated by content, `CDS_STORAGE_DEDUP=true`
+pub fn is_dedup_enabled() -> bool {
+    env::var("CDS_STORAGE_DEDUP").is_ok_and(|value| value == "true")
+}
+
+/// This function checks that the files of the tenant are on the local filesystem, as needed by the
+/// releases of the deployment targets: their `current` alias is a symlink atomically replaced,
+/// which an object store cannot provide.
+///
+/// # Arguments
+/// * storage (&dyn Storage): the storage backend
+/// * tenant (&Tenant): the tenant owning the files
+/// * feature (&str): the feature needing the files, named in the error
+///
+/// # Returns
+/// (Result<(), Error>): a `501 Not Implemented` error with the object store backends
+pub fn require_local_storage(
+    storage: &dyn Storage,
+    tenant: &Tenant,
+    feature: &str,
+) -> Result<(), Error> {
+    match storage.local_path(&tenant.root()) {
+        Some(_) => Ok(()),
+        None => Err(Error::NotImplemented(format!(
+            "The {} need the filesystem storage backend",
+            feature
+        ))),
+    }
+}
+
+/// This function copies the file or the directory at `path`, with all its content, to the local
+/// `destination`, so it can be processed on the local filesystem.
+///
+/// # Arguments
+/// * storage (&dyn Storage): the storage backend
+/// * path (&Path): the stored file or directory
+/// * destination (&Path): the local path of the copy, its parent must exist
+///
+/// # Returns
+/// (io::Result<()>): a `NotFound` error if `path` does not exist
+pub async fn download(storage: &dyn Storage, path: &Path, destination: &Path) -> io::Result<()> {
+    let meta = storage
+        .stat(path)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not found"))?;
+    let mut pending = vec![(path.to_path_buf(), destination.to_path_buf(), meta.is_dir)];
+    while let Some((path, destination, is_dir)) = pending.pop() {
+        if is_dir {
+            tokio::fs::create_dir_all(&destination).await?;
+            for child in storage.list(&path).await? {
+                let child_destination = destination.join(&child.name);
+                pending.push((path.join(&child.name), child_destination, child.is_dir));
+            }
+            continue;
+        }
+        let mut chunks = storage.get(&path).await?;
+        let mut file = tokio::fs::File::create(&destination).await?;
+        while let Some(chunk) = chunks.try_next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// This struct defines the storage on the local filesystem (or a mounted volume), relative to the
+/// working directory.
+///
+/// # Attributes
+/// * dedup (bool): `true` to store every distinct content once, in the blob store, and the files of
+///   the tenant trees as hard links to it
+pub struct FilesystemStorage {
+    dedup: bool,
+}
+
+fn metadata_of(path: &Path, metadata: &fs::Metadata) -> io::Result<ObjectMeta> {
+    Ok(ObjectMeta {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        path: path.to_path_buf(),
+        size: metadata.len(),
+        modified: metadata.modified()?,
+        is_dir: metadata.is_dir(),
+        revision: Some(format!(
+            "{:x}-{:x}-{:x}.{:x}",
+            metadata.dev(),
+            metadata.ino(),
+            metadata.ctime(),
+            metadata.ctime_nsec()
+        )),
+    })
+}
+
+#[async_trait(?Send)]
+impl Storage for FilesystemStorage {
+    async fn put(&self, path: &Path, source: &Path) -> io::Result<()> {
+        let path = path.to_path_buf();
+        let source = source.to_path_buf();
+        let dedup = self.dedup;
+        web::block(move || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            persist_file(&source, &path)?;
+            if dedup {
+                blobs::deduplicate(&path)?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    async fn get(&self, path: &Path) -> io::Result<ObjectStream> {
+        let file = tokio::fs::File::open(path).await?;
+        Ok(Box::pin(ReaderStream::new(file)))
+    }
+
+    async fn get_range(&self, path: &Path, offset: u64, length: u64) -> io::Result<ObjectStream> {
+        let mut file = tokio::fs::File::open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::pin(ReaderStream::new(file.take(length))))
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Option<ObjectMeta>> {
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata_of(path, &metadata).map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<ObjectMeta>> {
+        let path = path.to_path_buf();
+        web::block(move || {
+            let mut entries = vec![];
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                // the files being written are not visible until they are complete
+                if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+                    continue;
+                }
+                entries.push(metadata_of(&entry.path(), &entry.metadata()?)?);
+            }
+            Ok(entries)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        tokio::fs::create_dir_all(path).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        if self.dedup {
+            let path = path.to_path_buf();
+            return web::block(move || blobs::remove(&path))
+                .await
+                .map_err(io::Error::other)?;
+        }
+        if tokio::fs::symlink_metadata(path).await?.is_dir() {
+            tokio::fs::remove_dir_all(path).await
+        } else {
+            tokio::fs::remove_file(path).await
+        }
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = from.to_path_buf();
+        let to = to.to_path_buf();
+        web::block(move || {
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // the files are never modified in place, so the copy can share their content
+            let temp = temp_path(&to);
+            let copied = fs::hard_link(&from, &temp)
+                .or_else(|_| fs::copy(&from, &temp).map(|_| ()))
+                .and_then(|_| fs::rename(&temp, &to));
+            if copied.is_err() {
+                let _ = fs::remove_file(&temp);
+            }
+            copied
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(from, to).await
+    }
+
+    async fn adopt(&self, path: &Path) -> io::Result<()> {
+        if !self.dedup {
+            return Ok(());
+        }
+        let path = path.to_path_buf();
+        web::block(move || blobs::deduplicate(&path))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
@@ -0,0 +1,122 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+use std::path::PathBuf;
+
+use actix_web::HttpRequest;
+
+use crate::error::Error;
+
+/// The root of the whole CDS volume. Every tenant gets its own subdirectory.
+pub const BASE_PATH: &str = "entando-data";
+/// The tenant used when neither the caller nor its token select one.
+pub const DEFAULT_TENANT: &str = "primary";
+/// The header used by the internal API callers to select the tenant.
+pub const TENANT_HEADER: &str = "X-Entando-TenantCode";
+
+const MAX_TENANT_LENGTH: usize = 63;
+
+/// This struct defines a Tenant, which owns an isolated storage root under `BASE_PATH`:
+/// ```bash
+/// entando-data/
+/// ├── primary
+/// │   ├── archives
+/// │   ├── protected
+/// │   └── public
+/// └── tenant1
+///     ├── archives
+///     ├── protected
+///     └── public
+/// ```
+///
+/// # Attributes
+/// * code (String): the tenant code, which is also the name of its root directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    code: String,
+}
+
+impl Tenant {
+    /// This function validates the given tenant code and returns the matching Tenant.
+    /// Only ascii letters, digits, `-` and `_` are accepted, so a tenant code can never be used to
+    /// reach another tenant's root.
+    ///
+    /// # Arguments
+    /// * code (&str): the tenant code
+    ///
+    /// # Returns
+    /// (Result<Tenant, Error>): the tenant or a `400 Bad Request` error
+    pub fn new(code: &str) -> Result<Self, Error> {
+        let valid = !code.is_empty()
+            && code.len() <= MAX_TENANT_LENGTH
+            && code
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(Tenant {
+                code: code.to_string(),
+            })
+        } else {
+            Err(Error::BadRequest(format!("Invalid tenant code: {}", code)))
+        }
+    }
+
+    /// This function reads the tenant selected by the internal API caller through the
+    /// `X-Entando-TenantCode` header.
+    ///
+    /// # Arguments
+    /// * req (&HttpRequest): the request
+    ///
+    /// # Returns
+    /// (Result<Option<Tenant>, Error>): the tenant, `None` if the header is missing
+    pub fn from_header(req: &HttpRequest) -> Result<Option<Self>, Error> {
+        match req.headers().get(TENANT_HEADER) {
+            Some(value) => match value.to_str() {
+                Ok(code) => Tenant::new(code.trim()).map(Some),
+                Err(_) => Err(Error::BadRequest("Invalid tenant header".to_string())),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// The tenant root: `entando-data/<tenant>`
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(BASE_PATH).join(&self.code)
+    }
+
+    /// The tenant public directory: `entando-data/<tenant>/public`
+    pub fn public_path(&self) -> PathBuf {
+        self.root().join("public")
+    }
+
+    /// The tenant protected directory: `entando-data/<tenant>/protected`
+    pub fn protected_path(&self) -> PathBuf {
+        self.root().join("protected")
+    }
+
+    /// The tenant archives directory: `entando-data/<tenant>/archives`
+    pub fn archives_path(&self) -> PathBuf {
+        self.root().join("archives")
+    }
+}
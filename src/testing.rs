@@ -0,0 +1,121 @@
+/*++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+ + Copyright (c) 2022 Entando SRL.                                                                 +
+ + Permission is hereby granted, free of charge, to any person obtaining a copy of this software   +
+ + and associated documentation files (the "Software"), to deal in the Software without            +
+ + restriction, including without limitation the rights to use, copy, modify, merge, publish,      +
+ + distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the   +
+ + Software is furnished to do so, subject to the following conditions:                            +
+ +                                                                                                 +
+ + The above copyright notice and this permission notice shall be included in all copies or        +
+ + substantial portions of the Software.                                                           +
+ +                                                                                                 +
+ + THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR                      +
+ + IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,                        +
+ + FITNESS FOR A PARTICULAR PURPOSE AND NON INFRINGEMENT. IN NO EVENT SHALL THE                    +
+ + AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER                          +
+ + LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,                   +
+ + OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE                   +
+ + SOFTWARE.                                                                                       +
+ ++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++*/
+
+//! The helpers shared by the tests of the handlers: every test works on a tenant of its own,
+//! removed with all its files once the test is over, and sends the requests authorized for it.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+
+use actix_http::Request;
+use actix_web::test::TestRequest;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpMessage;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::auth::AuthConfig;
+use crate::jwks::TokenClaims;
+use crate::storage::{self, Storage};
+use crate::tenant::{Tenant, BASE_PATH, TENANT_HEADER};
+
+/// The hidden directories keeping files of a tenant outside its root
+const TENANT_DIRECTORIES: [&str; 3] = [".versions", ".trash", ".jobs"];
+
+/// This struct defines a tenant created for a single test, see `TestTenant::new`.
+pub struct TestTenant {
+    tenant: Tenant,
+}
+
+impl TestTenant {
+    /// This function returns a new tenant, with a random code so that the tests can run in
+    /// parallel.
+    pub fn new() -> Self {
+        let code = format!("test-{}", Uuid::new_v4().simple());
+        TestTenant {
+            tenant: Tenant::new(&code).unwrap(),
+        }
+    }
+}
+
+impl Deref for TestTenant {
+    type Target = Tenant;
+
+    fn deref(&self) -> &Tenant {
+        &self.tenant
+    }
+}
+
+impl Drop for TestTenant {
+    fn drop(&mut self) {
+        let base = Path::new(BASE_PATH);
+        let _ = fs::remove_dir_all(self.tenant.root());
+        for directory in TENANT_DIRECTORIES {
+            let _ = fs::remove_dir_all(base.join(directory).join(self.tenant.code()));
+        }
+        let rules = format!("{}.json", self.tenant.code());
+        let _ = fs::remove_file(base.join(".cache-rules").join(rules));
+    }
+}
+
+/// The filesystem storage, or the one selected by `CDS_STORAGE`
+pub fn storage() -> web::Data<dyn Storage> {
+    web::Data::from(storage::from_env().unwrap())
+}
+
+/// The permissive authorization configuration: every permission is granted
+pub fn auth_config() -> web::Data<AuthConfig> {
+    web::Data::new(AuthConfig::permissive())
+}
+
+/// This function returns a request of `tenant`, as sent with a valid token granting it.
+pub fn authorized(req: TestRequest, tenant: &Tenant) -> Request {
+    let req = req
+        .insert_header((TENANT_HEADER, tenant.code()))
+        .to_request();
+    let claims = json!({ "tenants": [tenant.code()] });
+    req.extensions_mut().insert(TokenClaims(claims));
+    req
+}
+
+/// This function returns the content type and the body of a multipart-form request. Every field is
+/// a name, an optional file name and a content.
+pub fn multipart(fields: &[(&str, Option<&str>, &[u8])]) -> (String, Bytes) {
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut body = vec![];
+    for (name, filename, content) in fields {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let disposition = match filename {
+            Some(filename) => format!("name=\"{}\"; filename=\"{}\"", name, filename),
+            None => format!("name=\"{}\"", name),
+        };
+        body.extend_from_slice(
+            format!("Content-Disposition: form-data; {}\r\n\r\n", disposition).as_bytes(),
+        );
+        body.extend_from_slice(content);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (
+        format!("multipart/form-data; boundary={}", boundary),
+        Bytes::from(body),
+    )
+}